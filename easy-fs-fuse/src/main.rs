@@ -1,7 +1,8 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::{fsck, BlockDevice, EasyFileSystem, FsError, FsResult, Inode};
 use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -12,29 +13,53 @@ const BLOCK_NUM: usize = 131072; //64*2048
 /// Wrapper for turning a File into a BlockDevice
 struct BlockFile(Mutex<File>);
 
-impl BlockDevice for BlockFile {
-    /// Read a block from file
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+impl BlockFile {
+    /// Read the blocks from `block_id`, the part beyond the end of file
+    /// reads as zeroes
+    fn read_at(&self, block_id: usize, buf: &mut [u8]) -> std::io::Result<()> {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))?;
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        buf[len..].fill(0);
+        Ok(())
     }
-    /// Write a block into file
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    /// Write the blocks from `block_id`
+    fn write_at(&self, block_id: usize, buf: &[u8]) -> std::io::Result<()> {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))?;
+        file.write_all(buf)
     }
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+impl BlockDevice for BlockFile {
+    /// Read a block from file
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        self.read_blocks(block_id, buf)
+    }
+    /// Write a block into file
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        self.write_blocks(block_id, buf)
+    }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        self.read_at(block_id, buf).map_err(|_| FsError::Io)
+    }
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        self.write_at(block_id, buf).map_err(|_| FsError::Io)
+    }
+    fn flush(&self) -> FsResult<()> {
+        self.0.lock().unwrap().sync_data().map_err(|_| FsError::Io)
+    }
 }
 
-/// Pack a directory into a easy-fs disk image
-fn easy_fs_pack() -> std::io::Result<()> {
+fn main() {
     let matches = App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
@@ -50,7 +75,40 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check an easy-fs image, after replaying its journal")
+                .arg(
+                    Arg::with_name("image")
+                        .required(true)
+                        .index(1)
+                        .help("Image to check"),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .short("r")
+                        .long("repair")
+                        .help("Repair the problems found"),
+                ),
+        )
         .get_matches();
+    if let Some(matches) = matches.subcommand_matches("fsck") {
+        let clean = easy_fs_fsck(matches).expect("Error when checking easy-fs!");
+        if !clean {
+            std::process::exit(1);
+        }
+        return;
+    }
+    easy_fs_pack(&matches).expect("Error when packing easy-fs!");
+}
+
+/// Turn an error of easy-fs into an I/O error of the packer
+fn fs_error(err: FsError) -> Error {
+    Error::new(ErrorKind::Other, format!("easy-fs: {:?}", err))
+}
+
+/// Pack a directory into a easy-fs disk image
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
     let src_path = matches.value_of("source").unwrap();
     let target_path = matches.value_of("target").unwrap();
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
@@ -63,76 +121,211 @@ fn easy_fs_pack() -> std::io::Result<()> {
         f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
         f
     })));
-    let efs = EasyFileSystem::create(block_file.clone(), BLOCK_NUM as u32, 1);
+    let efs = EasyFileSystem::create(block_file.clone(), BLOCK_NUM as u32, 1).map_err(fs_error)?;
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
-        .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
-        })
-        .collect();
-    for app in apps {
+    pack_dir(Path::new(src_path), Path::new(target_path), &root_inode)?;
+    efs.lock().sync().map_err(fs_error)?;
+    // list apps
+    for app in root_inode.ls().map_err(fs_error)? {
+        println!("{}", app);
+    }
+    Ok(())
+}
+
+/// Check an easy-fs disk image, return whether it is clean or repaired
+fn easy_fs_fsck(matches: &ArgMatches) -> std::io::Result<bool> {
+    let image = matches.value_of("image").unwrap();
+    let repair = matches.is_present("repair");
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open(image)?,
+    )));
+    let efs = EasyFileSystem::open(block_file).map_err(fs_error)?;
+    let report = fsck::check(&efs, repair).map_err(fs_error)?;
+    for problem in report.problems.iter() {
+        println!("{:?}", problem);
+    }
+    println!(
+        "{}: {} inodes, {} blocks, {} problems{}",
+        image,
+        report.inodes,
+        report.blocks,
+        report.problems.len(),
+        if report.repaired { " repaired" } else { "" },
+    );
+    efs.lock().unmount().map_err(fs_error)?;
+    Ok(report.is_clean() || report.repaired)
+}
+
+/// Pack the files listed in `src_dir` into the easy-fs directory `inode`
+///
+/// A file is named after its host file without the extension, and its data
+/// is the file of that name under `target_dir`. Subdirectories are packed
+/// recursively into directories of the same name, from the subdirectories
+/// of the same name under `target_dir`.
+fn pack_dir(src_dir: &Path, target_dir: &Path, inode: &Inode) -> std::io::Result<()> {
+    for dir_entry in read_dir(src_dir)? {
+        let dir_entry = dir_entry?;
+        let file_name = dir_entry.file_name().into_string().unwrap();
+        if dir_entry.file_type()?.is_dir() {
+            let dir = inode.mkdir(file_name.as_str()).map_err(fs_error)?;
+            pack_dir(&dir_entry.path(), &target_dir.join(&file_name), &dir)?;
+            continue;
+        }
+        let mut app = file_name;
+        if let Some(pos) = app.find('.') {
+            app.truncate(pos);
+        }
         // load app data (elf) from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
+        let mut host_file = File::open(target_dir.join(&app))?;
         let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
+        host_file.read_to_end(&mut all_data)?;
         // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
+        let file = inode.create(app.as_str()).map_err(fs_error)?;
         // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
-    }
-    // list apps
-    for app in root_inode.ls() {
-        println!("{}", app);
+        file.write_at(0, all_data.as_slice()).map_err(fs_error)?;
     }
     Ok(())
 }
 
+/// All images share the block cache, whose capacity some tests change, so
+/// tests run one at a time
+#[cfg(test)]
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Create an image file of `BLOCK_NUM` blocks for a test
+#[cfg(test)]
+fn test_block_file(path: &str) -> std::io::Result<Arc<BlockFile>> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+    f.set_len((BLOCK_NUM * BLOCK_SZ) as u64)?;
+    Ok(Arc::new(BlockFile(Mutex::new(f))))
+}
+
+/// A block device in memory, which counts its read and write requests,
+/// and crashes when it is told to
+#[cfg(test)]
+struct MemBlockDevice {
+    blocks: Mutex<Vec<[u8; BLOCK_SZ]>>,
+    reads: std::sync::atomic::AtomicUsize,
+    writes: std::sync::atomic::AtomicUsize,
+    /// Number of blocks written before the crash, after which writes are
+    /// silently lost
+    crash_after: std::sync::atomic::AtomicUsize,
+    /// Whether writes fail
+    broken: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(test)]
+impl MemBlockDevice {
+    fn new(blocks: usize) -> Arc<Self> {
+        Arc::new(Self {
+            blocks: Mutex::new(vec![[0; BLOCK_SZ]; blocks]),
+            reads: Default::default(),
+            writes: Default::default(),
+            crash_after: usize::MAX.into(),
+            broken: false.into(),
+        })
+    }
+    /// Crash after writing `blocks` more blocks, a request may be cut
+    /// short by the crash
+    fn crash_after(&self, blocks: usize) {
+        use std::sync::atomic::Ordering::Relaxed;
+        self.crash_after.store(blocks, Relaxed);
+    }
+    /// Make writes fail from now on, or work again
+    fn set_broken(&self, broken: bool) {
+        use std::sync::atomic::Ordering::Relaxed;
+        self.broken.store(broken, Relaxed);
+    }
+    /// A new device with the blocks on this one, as found after a reboot
+    fn reboot(&self) -> Arc<Self> {
+        let device = Self::new(0);
+        *device.blocks.lock().unwrap() = self.blocks.lock().unwrap().clone();
+        device
+    }
+    /// Reads and writes since the last call
+    fn take_counts(&self) -> (usize, usize) {
+        use std::sync::atomic::Ordering::Relaxed;
+        (self.reads.swap(0, Relaxed), self.writes.swap(0, Relaxed))
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for MemBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        self.read_blocks(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        self.write_blocks(block_id, buf)
+    }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        use std::sync::atomic::Ordering::Relaxed;
+        self.reads.fetch_add(1, Relaxed);
+        let blocks = self.blocks.lock().unwrap();
+        for (i, block) in buf.chunks_exact_mut(BLOCK_SZ).enumerate() {
+            block.copy_from_slice(blocks.get(block_id + i).ok_or(FsError::Io)?);
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        use std::sync::atomic::Ordering::Relaxed;
+        self.writes.fetch_add(1, Relaxed);
+        if self.broken.load(Relaxed) {
+            return Err(FsError::Io);
+        }
+        let mut blocks = self.blocks.lock().unwrap();
+        for (i, block) in buf.chunks_exact(BLOCK_SZ).enumerate() {
+            let dst = blocks.get_mut(block_id + i).ok_or(FsError::Io)?;
+            let crashed = self
+                .crash_after
+                .fetch_update(Relaxed, Relaxed, |left| left.checked_sub(1))
+                .is_err();
+            if !crashed {
+                dst.copy_from_slice(block);
+            }
+        }
+        Ok(())
+    }
+}
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len(BLOCK_NUM * BLOCK_SZ).unwrap();
-        f
-    })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let block_file = test_block_file("target/fs.img")?;
+    EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
     let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    for name in root_inode.ls().unwrap() {
         println!("{}", name);
     }
     let filea = root_inode.find("filea").unwrap();
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
     //let mut buffer = [0u8; BLOCK_SZ];
     let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
+    let len = filea.read_at(0, &mut buffer).unwrap();
     assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
 
     let mut random_str_test = |len: usize| {
-        filea.clear();
-        assert_eq!(filea.read_at(0, &mut buffer), 0,);
+        filea.clear().unwrap();
+        assert_eq!(filea.read_at(0, &mut buffer), Ok(0));
         let mut str = String::new();
         use rand;
         // random digit
         for _ in 0..len {
             str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
         loop {
-            let len = filea.read_at(offset, &mut read_buffer);
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
             if len == 0 {
                 break;
             }
@@ -153,3 +346,738 @@ fn efs_test() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn efs_dir_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let block_file = test_block_file("target/fs_dir.img")?;
+    let efs = EasyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(root_inode.ls().unwrap().is_empty());
+
+    let usr = root_inode.mkdir("usr").unwrap();
+    assert_eq!(usr.is_dir(), Ok(true));
+    assert_eq!(root_inode.mkdir("usr").err(), Some(FsError::Exists));
+    assert!(root_inode.mkdir("/usr/bin/").is_ok());
+    let hello = root_inode.create("/usr/bin/hello").unwrap();
+    assert_eq!(hello.is_dir(), Ok(false));
+    hello.write_at(0, b"hello").unwrap();
+    assert_eq!(root_inode.create("usr/bin/hello").err(), Some(FsError::Exists));
+    // a file is not a directory
+    assert_eq!(root_inode.create("usr/bin/hello/world").err(), Some(FsError::NotDir));
+    assert_eq!(root_inode.find("usr/bin/hello/.").err(), Some(FsError::NotDir));
+    assert_eq!(hello.ls(), Err(FsError::NotDir));
+    assert_eq!(usr.read_at(0, &mut [0u8; 4]), Err(FsError::IsDir));
+    assert_eq!(usr.write_at(0, b"usr"), Err(FsError::IsDir));
+    assert_eq!(root_inode.mkdir("usr/lib/x").err(), Some(FsError::NotFound));
+    assert_eq!(
+        root_inode.create("usr/this_name_is_far_too_long_for_easy_fs").err(),
+        Some(FsError::NameTooLong)
+    );
+
+    let mut buffer = [0u8; 16];
+    for path in [
+        "/usr/bin/hello",
+        "usr/bin/hello",
+        "usr//bin/./hello",
+        "/usr/bin/../bin/hello",
+        "/../../usr/bin/hello",
+    ] {
+        let inode = root_inode.find(path).unwrap();
+        assert_eq!(inode.read_at(0, &mut buffer), Ok(5), "{}", path);
+        assert_eq!(&buffer[..5], b"hello");
+    }
+    let bin = usr.find("bin").unwrap();
+    assert_eq!(bin.find("hello").unwrap().read_at(0, &mut buffer), Ok(5));
+    assert_eq!(bin.find("../../usr/bin/hello").unwrap().read_at(0, &mut buffer), Ok(5));
+    assert_eq!(bin.find("/usr/bin/hello").unwrap().read_at(0, &mut buffer), Ok(5));
+    assert!(bin.find("..").unwrap().find("bin").is_ok());
+    assert_eq!(root_inode.ls().unwrap(), ["usr"]);
+    assert_eq!(usr.ls().unwrap(), ["bin"]);
+    assert_eq!(bin.ls().unwrap(), ["hello"]);
+
+    // only empty directories are removed
+    assert_eq!(root_inode.rmdir("usr"), Err(FsError::NotEmpty));
+    assert_eq!(root_inode.rmdir("usr/bin"), Err(FsError::NotEmpty));
+    assert_eq!(root_inode.rmdir("usr/bin/hello"), Err(FsError::NotDir));
+    assert_eq!(root_inode.rmdir("usr/bin/."), Err(FsError::Invalid));
+    assert_eq!(root_inode.rmdir("usr/bin/.."), Err(FsError::Invalid));
+    assert_eq!(root_inode.rmdir("/"), Err(FsError::Invalid));
+    assert!(root_inode.mkdir("usr/lib").is_ok());
+    assert_eq!(root_inode.rmdir("usr/lib/"), Ok(()));
+    assert_eq!(root_inode.find("usr/lib").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.rmdir("usr/lib"), Err(FsError::NotFound));
+    assert_eq!(usr.ls().unwrap(), ["bin"]);
+    // the removed entry is reused
+    assert!(usr.mkdir("share").is_ok());
+    assert_eq!(usr.ls().unwrap(), ["bin", "share"]);
+
+    // everything is on the disk
+    let efs = EasyFileSystem::open(efs.lock().block_device.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.find("usr/share/..").unwrap().ls().unwrap(), ["bin", "share"]);
+    assert_eq!(root_inode.find("usr/bin/hello").unwrap().read_at(0, &mut buffer), Ok(5));
+    Ok(())
+}
+
+#[test]
+fn pack_dir_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    // apps are listed in the source directory, and read from the target directory
+    let src = Path::new("target/pack_test/src");
+    let target = Path::new("target/pack_test/target");
+    let _ = std::fs::remove_dir_all("target/pack_test");
+    for dir in [src.join("sub/subsub"), target.join("sub/subsub")] {
+        std::fs::create_dir_all(dir)?;
+    }
+    for (path, data) in [("app0", "zero"), ("sub/app1", "one"), ("sub/subsub/app2", "two")] {
+        File::create(src.join(format!("{}.bin", path)))?;
+        File::create(target.join(path))?.write_all(data.as_bytes())?;
+    }
+    let block_file = test_block_file("target/fs_pack.img")?;
+    let efs = EasyFileSystem::create(block_file, 4096, 1).map_err(fs_error)?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    pack_dir(src, target, &root_inode)?;
+
+    let mut names = root_inode.ls().map_err(fs_error)?;
+    names.sort();
+    assert_eq!(names, ["app0", "sub"]);
+    let mut buffer = [0u8; 16];
+    for (path, data) in [("app0", "zero"), ("sub/app1", "one"), ("sub/subsub/app2", "two")] {
+        let len = root_inode.find(path).unwrap().read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], data.as_bytes());
+    }
+    // packing a name twice is reported instead of crashing
+    let err = pack_dir(src, target, &root_inode).unwrap_err();
+    assert!(err.to_string().contains("Exists"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn efs_unlink_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let block_file = test_block_file("target/fs_unlink.img")?;
+    let efs = EasyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(root_inode.mkdir("dir").is_ok());
+    let used = || {
+        let efs = efs.lock();
+        (efs.used_inodes().unwrap(), efs.used_data_blocks().unwrap())
+    };
+    let before = used();
+
+    // files of every size: direct, indirect1 and indirect2 blocks
+    let sizes = [0, 1, BLOCK_SZ, 28 * BLOCK_SZ + 1, (28 + 128) * BLOCK_SZ + 1, 300 * BLOCK_SZ];
+    let data: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| i as u8).collect();
+    for round in 0..4 {
+        for (i, &size) in sizes.iter().enumerate() {
+            let path = format!("dir/file{}", i);
+            let file = root_inode.create(&path).unwrap();
+            assert_eq!(file.write_at(0, &data[..size]), Ok(size));
+        }
+        assert_eq!(used().0, before.0 + sizes.len());
+        assert_eq!(root_inode.find("dir").unwrap().ls().unwrap().len(), sizes.len());
+        // remove in a different order every round
+        for i in (0..sizes.len()).map(|i| (i * 5 + round) % sizes.len()) {
+            let path = format!("/dir/./file{}", i);
+            assert_eq!(root_inode.unlink(&path), Ok(()), "{}", path);
+            assert_eq!(root_inode.find(&path).err(), Some(FsError::NotFound));
+            assert_eq!(root_inode.unlink(&path), Err(FsError::NotFound));
+        }
+        assert!(root_inode.find("dir").unwrap().ls().unwrap().is_empty());
+        // the directory keeps its block, and nothing else is left
+        assert_eq!(used(), before);
+    }
+
+    // directories are not unlinked, and rmdir frees their inodes
+    assert_eq!(root_inode.unlink("dir"), Err(FsError::IsDir));
+    assert_eq!(root_inode.rmdir("dir"), Ok(()));
+    let (inodes, _) = used();
+    assert_eq!(inodes, 1);
+    // the inode numbers are reused
+    let file = root_inode.create("again").unwrap();
+    file.write_at(0, b"again").unwrap();
+    assert_eq!(used().0, 2);
+    Ok(())
+}
+
+#[test]
+fn efs_link_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let block_file = test_block_file("target/fs_link.img")?;
+    let efs = EasyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let root_stat = root_inode.stat().unwrap();
+    assert_eq!(root_stat.ino, 0);
+    assert_eq!(root_stat.type_, easy_fs::DiskInodeType::Directory);
+    assert_eq!(root_stat.nlink, 2);
+
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, b"linked").unwrap();
+    let stat = file.stat().unwrap();
+    assert_eq!(stat.type_, easy_fs::DiskInodeType::File);
+    assert_eq!(stat.size, 6);
+    assert_eq!(stat.nlink, 1);
+    let used = efs.lock().used_inodes().unwrap();
+
+    // a directory counts `.` and the `..` of its subdirectories
+    let dir = root_inode.mkdir("dir").unwrap();
+    assert_eq!(dir.stat().unwrap().nlink, 2);
+    assert_eq!(root_inode.stat().unwrap().nlink, 3);
+    assert!(root_inode.mkdir("dir/sub").is_ok());
+    assert_eq!(dir.stat().unwrap().nlink, 3);
+    assert_eq!(root_inode.rmdir("dir/sub"), Ok(()));
+    assert_eq!(dir.stat().unwrap().nlink, 2);
+
+    assert_eq!(root_inode.link("file", "dir/link"), Ok(()));
+    assert_eq!(root_inode.link("file", "dir/link"), Err(FsError::Exists));
+    assert_eq!(root_inode.link("missing", "dir/other"), Err(FsError::NotFound));
+    assert_eq!(root_inode.link("dir", "dir_link"), Err(FsError::IsDir));
+    assert_eq!(root_inode.link("file", "dir/."), Err(FsError::Exists));
+    assert_eq!(file.stat().unwrap().nlink, 2);
+    let link = root_inode.find("dir/link").unwrap();
+    assert_eq!(link.stat().unwrap().ino, stat.ino);
+    assert_eq!(link.stat().unwrap().nlink, 2);
+    assert_eq!(efs.lock().used_inodes().unwrap(), used + 1);
+
+    // the data is kept until the last link is removed
+    assert_eq!(root_inode.unlink("file"), Ok(()));
+    assert_eq!(link.stat().unwrap().nlink, 1);
+    let mut buffer = [0u8; 16];
+    let len = link.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"linked");
+    assert_eq!(root_inode.unlink("dir/link"), Ok(()));
+    assert_eq!(root_inode.rmdir("dir"), Ok(()));
+    assert_eq!(root_inode.stat().unwrap().nlink, 2);
+    assert_eq!(efs.lock().used_inodes().unwrap(), used - 1);
+    Ok(())
+}
+
+#[test]
+fn block_cache_test() {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let device = MemBlockDevice::new(4096);
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let mut buffer = vec![0u8; data.len()];
+
+    // the smallest cache does not run out, even with indirect2 blocks
+    easy_fs::set_block_cache_capacity(1);
+    let file = root_inode.create("small").unwrap();
+    assert_eq!(file.write_at(0, &data), Ok(data.len()));
+    assert_eq!(file.read_at(0, &mut buffer), Ok(data.len()));
+    assert!(buffer == data);
+
+    // changes stay in the cache until they are synced, only the journal
+    // is written, a descriptor with the blocks and a commit block for each
+    // of the two transactions
+    easy_fs::set_block_cache_capacity(64);
+    efs.lock().sync().unwrap();
+    device.take_counts();
+    let file = root_inode.create("deferred").unwrap();
+    file.write_at(0, b"deferred").unwrap();
+    assert_eq!(device.take_counts().1, 4);
+    file.sync().unwrap();
+    assert!(device.take_counts().1 > 0);
+    file.sync().unwrap();
+    assert_eq!(device.take_counts().1, 0);
+    let efs2 = EasyFileSystem::open(device.clone()).unwrap();
+    let len = EasyFileSystem::root_inode(&efs2)
+        .find("deferred")
+        .unwrap()
+        .read_at(0, &mut buffer)
+        .unwrap();
+    assert_eq!(&buffer[..len], b"deferred");
+
+    // parts of blocks which fit in the cache are read from the device
+    // only once
+    let file = root_inode.create("cached").unwrap();
+    file.write_at(0, &data[..32 * BLOCK_SZ]).unwrap();
+    let read_parts = |buffer: &mut [u8]| {
+        for i in 0..32 {
+            assert_eq!(file.read_at(i * BLOCK_SZ + 1, &mut buffer[..8]), Ok(8));
+        }
+    };
+    read_parts(&mut buffer);
+    device.take_counts();
+    for _ in 0..4 {
+        read_parts(&mut buffer);
+    }
+    assert_eq!(device.take_counts().0, 0);
+    // a dirty block is written back when it is evicted
+    for i in 0..32 {
+        file.write_at(i * BLOCK_SZ + 1, b"dirty").unwrap();
+    }
+    device.take_counts();
+    easy_fs::set_block_cache_capacity(4);
+    assert!(device.take_counts().1 > 0);
+    read_parts(&mut buffer);
+    assert!(device.take_counts().0 > 0);
+    easy_fs::set_block_cache_capacity(16);
+}
+
+#[test]
+fn block_batch_test() {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let device = MemBlockDevice::new(4096);
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data: Vec<u8> = (0..64 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let mut buffer = vec![0u8; data.len()];
+    let file = root_inode.create("batch").unwrap();
+    efs.lock().sync().unwrap();
+    device.take_counts();
+
+    // whole blocks go to the device in a request per contiguous run, the
+    // direct blocks and those after the indirect1 block, and the journal
+    // takes two more requests, while new blocks are never read
+    assert_eq!(file.write_at(0, &data), Ok(data.len()));
+    assert_eq!(device.take_counts(), (0, 4));
+    // blocks written whole are neither zeroed nor cached, so they are read
+    // in a request per run, while the indirect1 block stays cached
+    assert_eq!(file.read_at(0, &mut buffer), Ok(data.len()));
+    assert!(buffer == data);
+    assert_eq!(device.take_counts(), (2, 0));
+    efs.lock().sync().unwrap();
+    device.take_counts();
+    assert_eq!(file.read_at(0, &mut buffer), Ok(data.len()));
+    assert!(buffer == data);
+    assert_eq!(device.take_counts(), (2, 0));
+    // unaligned ends go through the cache, and cached blocks are newer
+    // than the device
+    file.write_at(BLOCK_SZ + 3, b"cached").unwrap();
+    let len = file.read_at(3, &mut buffer[..40 * BLOCK_SZ]).unwrap();
+    assert_eq!(len, 40 * BLOCK_SZ);
+    assert_eq!(&buffer[BLOCK_SZ..BLOCK_SZ + 6], b"cached");
+    assert_eq!(&buffer[..BLOCK_SZ], &data[3..BLOCK_SZ + 3]);
+    // a whole block write replaces a dirty cached block
+    file.write_at(BLOCK_SZ, &data[..BLOCK_SZ]).unwrap();
+    efs.lock().sync().unwrap();
+    assert_eq!(file.read_at(0, &mut buffer), Ok(data.len()));
+    assert_eq!(&buffer[BLOCK_SZ..2 * BLOCK_SZ], &data[..BLOCK_SZ]);
+
+    // freed blocks read as zeroes when they are allocated again
+    file.clear().unwrap();
+    assert_eq!(file.write_at(10 * BLOCK_SZ, b"end"), Ok(3));
+    assert_eq!(file.read_at(0, &mut buffer), Ok(10 * BLOCK_SZ + 3));
+    assert!(buffer[..10 * BLOCK_SZ].iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn efs_truncate_test() {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let device = MemBlockDevice::new(4096);
+    let efs = EasyFileSystem::create(device, 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("sparse").unwrap();
+    let used = efs.lock().used_data_blocks().unwrap();
+    let used_more = || efs.lock().used_data_blocks().unwrap() - used;
+
+    // a write past the end, even past the end of the device, leaves a hole
+    // which reads as zeroes, with only the written block and the indirect2
+    // and indirect1 blocks leading to it allocated
+    let far = 10000 * BLOCK_SZ;
+    assert_eq!(file.write_at(far + 3, b"far"), Ok(3));
+    assert_eq!(file.stat().unwrap().size as usize, far + 6);
+    assert_eq!(used_more(), 3);
+    let mut buffer = vec![0xffu8; 30 * BLOCK_SZ];
+    assert_eq!(file.read_at(far - BLOCK_SZ, &mut buffer), Ok(BLOCK_SZ + 6));
+    assert!(buffer[..BLOCK_SZ + 3].iter().all(|&byte| byte == 0));
+    assert_eq!(&buffer[BLOCK_SZ + 3..BLOCK_SZ + 6], b"far");
+    // filling a hole allocates its blocks, and the indirect1 block
+    let data: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    assert_eq!(file.write_at(BLOCK_SZ, &data), Ok(data.len()));
+    assert_eq!(used_more(), 3 + 40 + 1);
+
+    // shrinking frees the blocks past the end, and the indirect blocks
+    // left empty
+    file.truncate(20 * BLOCK_SZ + 5).unwrap();
+    assert_eq!(file.stat().unwrap().size as usize, 20 * BLOCK_SZ + 5);
+    assert_eq!(used_more(), 20);
+    // growing leaves a hole, and the rest of the last block is zeroed
+    file.truncate(30 * BLOCK_SZ).unwrap();
+    assert_eq!(used_more(), 20);
+    assert_eq!(file.read_at(0, &mut buffer), Ok(30 * BLOCK_SZ));
+    assert!(buffer[..BLOCK_SZ].iter().all(|&byte| byte == 0));
+    assert_eq!(&buffer[BLOCK_SZ..20 * BLOCK_SZ + 5], &data[..19 * BLOCK_SZ + 5]);
+    assert!(buffer[20 * BLOCK_SZ + 5..].iter().all(|&byte| byte == 0));
+    // an indirect block with only holes left is freed as well
+    file.write_at(50 * BLOCK_SZ, b"indirect1").unwrap();
+    file.write_at(200 * BLOCK_SZ, b"indirect2").unwrap();
+    assert_eq!(used_more(), 20 + 2 + 3);
+    file.truncate(100 * BLOCK_SZ).unwrap();
+    assert_eq!(used_more(), 20 + 2);
+    file.truncate(40 * BLOCK_SZ).unwrap();
+    assert_eq!(used_more(), 20);
+    assert!(fsck::check(&efs, false).unwrap().is_clean());
+    file.truncate(0).unwrap();
+    assert_eq!(used_more(), 0);
+
+    let usr = root_inode.mkdir("usr").unwrap();
+    assert_eq!(usr.truncate(0), Err(FsError::IsDir));
+    assert_eq!(file.truncate(usize::MAX), Err(FsError::NoSpace));
+    assert_eq!(file.write_at(usize::MAX / 2, b"x"), Err(FsError::NoSpace));
+    assert!(fsck::check(&efs, false).unwrap().is_clean());
+}
+
+#[test]
+fn multiple_images_test() {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let devices = [MemBlockDevice::new(4096), MemBlockDevice::new(2048)];
+    let filesystems: Vec<_> = devices
+        .iter()
+        .zip([4096, 2048])
+        .map(|(device, blocks)| EasyFileSystem::create(device.clone(), blocks, 1).unwrap())
+        .collect();
+    // the same paths on both images, in the same blocks
+    for (i, efs) in filesystems.iter().enumerate() {
+        let root_inode = EasyFileSystem::root_inode(efs);
+        let file = root_inode.create("file").unwrap();
+        file.write_at(0, format!("image {}", i).as_bytes()).unwrap();
+        assert!(root_inode.mkdir(&format!("dir{}", i)).is_ok());
+    }
+    let mut buffer = [0u8; 16];
+    for (i, efs) in filesystems.iter().enumerate() {
+        let root_inode = EasyFileSystem::root_inode(efs);
+        let len = root_inode.find("file").unwrap().read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], format!("image {}", i).as_bytes());
+        let mut names = root_inode.ls().unwrap();
+        names.sort();
+        assert_eq!(names, ["dir".to_string() + &i.to_string(), "file".to_string()]);
+    }
+
+    // unmounting writes back the blocks of one image only
+    devices[0].take_counts();
+    devices[1].take_counts();
+    filesystems[0].lock().unmount().unwrap();
+    assert!(devices[0].take_counts().1 > 0);
+    assert_eq!(devices[1].take_counts().1, 0);
+    // and its blocks are read from the device again
+    let efs = EasyFileSystem::open(devices[0].clone()).unwrap();
+    let len = EasyFileSystem::root_inode(&efs)
+        .find("file")
+        .unwrap()
+        .read_at(0, &mut buffer)
+        .unwrap();
+    assert_eq!(&buffer[..len], b"image 0");
+    assert!(devices[0].take_counts().0 > 0);
+    let len = EasyFileSystem::root_inode(&filesystems[1])
+        .find("file")
+        .unwrap()
+        .read_at(0, &mut buffer)
+        .unwrap();
+    assert_eq!(&buffer[..len], b"image 1");
+    assert_eq!(devices[1].take_counts().0, 0);
+}
+
+#[test]
+fn efs_error_test() {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    // not an easy-fs image
+    let device = MemBlockDevice::new(64);
+    assert_eq!(EasyFileSystem::open(device.clone()).err(), Some(FsError::Corrupted));
+    // too small for the inode area
+    assert_eq!(EasyFileSystem::create(device, 64, 1).err(), Some(FsError::NoSpace));
+    // an image of the layout without link counts and the journal
+    let device = MemBlockDevice::new(4096);
+    EasyFileSystem::create(device.clone(), 4096, 1).unwrap().lock().unmount().unwrap();
+    patch_block(&device, 0, 0, &0x3b800001u32.to_le_bytes());
+    assert_eq!(EasyFileSystem::open(device).err(), Some(FsError::Corrupted));
+    // an inode whose type is none of the known ones
+    let device = MemBlockDevice::new(4096);
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+    let ino = EasyFileSystem::root_inode(&efs).create("c").unwrap().stat().unwrap().ino;
+    let mut fs = efs.lock();
+    fs.unmount().unwrap();
+    let (block_id, offset) = fs.get_disk_inode_pos(ino);
+    drop(fs);
+    patch_block(&device, block_id, offset + 124, &7u32.to_le_bytes());
+    let efs = EasyFileSystem::open(device).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).find("c").unwrap();
+    assert_eq!(file.stat().err(), Some(FsError::Corrupted));
+    assert_eq!(file.is_dir(), Err(FsError::Corrupted));
+    assert_eq!(file.read_at(0, &mut [0u8; 1]), Err(FsError::Corrupted));
+
+    // running out of data blocks cuts a write short after the chunks
+    // which fit, and a write with no room for its first chunk fails
+    let device = MemBlockDevice::new(1200);
+    let efs = EasyFileSystem::create(device, 1200, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("big").unwrap();
+    let used = efs.lock().used_data_blocks().unwrap();
+    let data = vec![1u8; 300 * BLOCK_SZ];
+    let written = file.write_at(0, &data).unwrap();
+    assert!(written > 0 && written < data.len());
+    assert_eq!(file.stat().unwrap().size as usize, written);
+    let blocks = efs.lock().used_data_blocks().unwrap();
+    assert_eq!(file.write_at(written, &data), Err(FsError::NoSpace));
+    assert_eq!(efs.lock().used_data_blocks().unwrap(), blocks);
+    assert_eq!(file.stat().unwrap().size as usize, written);
+    // whatever fits is still written
+    let mut len = written;
+    while file.write_at(len, &data[..BLOCK_SZ]).is_ok() {
+        len += BLOCK_SZ;
+    }
+    assert!(len > written);
+    assert_eq!(file.stat().unwrap().size as usize, len);
+    assert_eq!(root_inode.mkdir("dir").err(), Some(FsError::NoSpace));
+    assert_eq!(root_inode.find("dir").err(), Some(FsError::NotFound));
+    // and freeing blocks makes room again, leaking nothing
+    file.clear().unwrap();
+    assert_eq!(efs.lock().used_data_blocks().unwrap(), used);
+    assert!(root_inode.mkdir("dir").is_ok());
+    assert_eq!(file.write_at(usize::MAX, b"x"), Err(FsError::NoSpace));
+
+    // running out of inodes
+    let device = MemBlockDevice::new(8192);
+    let efs = EasyFileSystem::create(device, 8192, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut created = 1;
+    loop {
+        match root_inode.create(&format!("f{}", created)) {
+            Ok(_) => created += 1,
+            Err(err) => {
+                assert_eq!(err, FsError::NoSpace);
+                break;
+            }
+        }
+    }
+    assert_eq!(created, 4096);
+}
+
+#[test]
+fn rollback_test() {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let device = MemBlockDevice::new(4096);
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("a").unwrap();
+    file.write_at(0, &[1u8; 3 * BLOCK_SZ]).unwrap();
+    root_inode.sync().unwrap();
+    let inodes = efs.lock().used_inodes().unwrap();
+    let blocks = efs.lock().used_data_blocks().unwrap();
+
+    // operations which fail to commit leave nothing behind, even when
+    // they have done half of their work
+    device.set_broken(true);
+    assert_eq!(root_inode.link("a", "b"), Err(FsError::Io));
+    assert_eq!(root_inode.mkdir("dir").err(), Some(FsError::Io));
+    assert_eq!(root_inode.unlink("a"), Err(FsError::Io));
+    device.set_broken(false);
+    assert_eq!(root_inode.ls().unwrap(), vec!["a"]);
+    assert_eq!(file.stat().unwrap().nlink, 1);
+    let mut buf = [0u8; 3 * BLOCK_SZ];
+    assert_eq!(file.read_at(0, &mut buf).unwrap(), buf.len());
+    assert!(buf.iter().all(|&byte| byte == 1));
+    assert_eq!(efs.lock().used_inodes().unwrap(), inodes);
+    assert_eq!(efs.lock().used_data_blocks().unwrap(), blocks);
+
+    // and the filesystem goes on as if they never happened
+    root_inode.link("a", "b").unwrap();
+    root_inode.unlink("a").unwrap();
+    efs.lock().unmount().unwrap();
+    let efs = EasyFileSystem::open(device).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let file = EasyFileSystem::root_inode(&efs).find("b").unwrap();
+    assert_eq!(file.stat().unwrap().nlink, 1);
+    assert_eq!(file.read_at(0, &mut buf).unwrap(), buf.len());
+}
+
+/// Operations of all kinds, some of which take more than a chunk of a
+/// write or free indirect blocks
+#[cfg(test)]
+fn crash_workload(root_inode: &Inode) -> FsResult<()> {
+    let data: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    root_inode.mkdir("dir")?;
+    root_inode.mkdir("dir/sub")?;
+    root_inode.create("dir/a")?.write_at(0, &data)?;
+    root_inode.create("b")?.write_at(3 * BLOCK_SZ + 5, b"sparse")?;
+    root_inode.link("dir/a", "c")?;
+    root_inode.unlink("dir/a")?;
+    root_inode.create("dir/sub/d")?.write_at(0, &data[..40 * BLOCK_SZ])?;
+    root_inode.find("c")?.clear()?;
+    root_inode.unlink("dir/sub/d")?;
+    root_inode.rmdir("dir/sub")?;
+    root_inode.find("b")?.write_at(0, &data[..BLOCK_SZ + 7])?;
+    Ok(())
+}
+
+/// Read every file under the directory `path`, and remove them along with
+/// the directories
+#[cfg(test)]
+fn remove_all(root_inode: &Inode, path: &str) -> FsResult<()> {
+    let mut buffer = vec![0u8; BLOCK_SZ];
+    for name in root_inode.find(path)?.ls()? {
+        let path = format!("{}/{}", path, name);
+        let inode = root_inode.find(&path)?;
+        if inode.is_dir()? {
+            remove_all(root_inode, &path)?;
+            root_inode.rmdir(&path)?;
+        } else {
+            let size = inode.stat()?.size as usize;
+            let mut offset = 0;
+            while offset < size {
+                offset += inode.read_at(offset, &mut buffer)?;
+            }
+            root_inode.unlink(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_test() {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let setup = || {
+        let device = MemBlockDevice::new(4096);
+        let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+        (device, efs)
+    };
+    // count the blocks written by the workload, until it is synced
+    let (device, efs) = setup();
+    device.crash_after(usize::MAX);
+    crash_workload(&EasyFileSystem::root_inode(&efs)).unwrap();
+    efs.lock().unmount().unwrap();
+    let total = usize::MAX - device.crash_after.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(total > 0);
+
+    // whenever the device crashes, the filesystem is consistent after it
+    // is opened again, nothing is lost or referred to twice
+    for crash_after in 0..total {
+        let (device, efs) = setup();
+        device.crash_after(crash_after);
+        // nothing reaches the device after the crash, so whatever the
+        // filesystem does then, including failing on stale blocks, is lost
+        let _ = crash_workload(&EasyFileSystem::root_inode(&efs));
+        let _ = efs.lock().unmount();
+        let efs = EasyFileSystem::open(device.reboot()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        remove_all(&root_inode, "").unwrap();
+        assert_eq!(root_inode.ls().unwrap(), Vec::<String>::new());
+        let efs = efs.lock();
+        assert_eq!(efs.used_inodes().unwrap(), 1);
+        // the root keeps a block of its directory entries
+        assert_eq!(efs.used_data_blocks().unwrap(), 1);
+    }
+}
+
+/// Overwrite bytes of a block on a device, behind the filesystem
+#[cfg(test)]
+fn patch_block(device: &MemBlockDevice, block_id: u32, offset: usize, bytes: &[u8]) {
+    let mut blocks = device.blocks.lock().unwrap();
+    blocks[block_id as usize][offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+#[test]
+fn fsck_test() {
+    use fsck::{DirentError, Problem};
+    let _guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let device = MemBlockDevice::new(4096);
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    crash_workload(&root_inode).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!(report.inodes, efs.lock().used_inodes().unwrap());
+    assert_eq!(report.blocks, efs.lock().used_data_blocks().unwrap());
+
+    // break the filesystem behind its back
+    let a = root_inode.create("a").unwrap();
+    a.write_at(0, &[1u8; 3 * BLOCK_SZ]).unwrap();
+    let b = root_inode.create("dir/b").unwrap();
+    b.write_at(0, &[2u8; BLOCK_SZ]).unwrap();
+    let gone = root_inode.create("gone").unwrap().stat().unwrap().ino;
+    let (a, b) = (a.stat().unwrap().ino, b.stat().unwrap().ino);
+    let mut fs = efs.lock();
+    let orphan_inode = fs.alloc_inode().unwrap();
+    let orphan_block = fs.alloc_data().unwrap();
+    fs.dealloc_inode(gone).unwrap();
+    fs.unmount().unwrap();
+    let a_pos = fs.get_disk_inode_pos(a);
+    let b_pos = fs.get_disk_inode_pos(b);
+    let root_pos = fs.get_disk_inode_pos(0);
+    drop(fs);
+    let direct = |(block_id, offset): (u32, usize), i: usize| {
+        let blocks = device.blocks.lock().unwrap();
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&blocks[block_id as usize][offset + 4 + 4 * i..offset + 8 + 4 * i]);
+        u32::from_le_bytes(bytes)
+    };
+    // a takes the block of b, and has a wrong link count
+    let shared = direct(b_pos, 0);
+    let lost = direct(a_pos, 1);
+    patch_block(&device, a_pos.0, a_pos.1 + 8, &shared.to_le_bytes());
+    patch_block(&device, a_pos.0, a_pos.1 + 120, &5u32.to_le_bytes());
+    // the name of the fourth entry of the root, which is b of the
+    // workload, is not UTF-8
+    patch_block(&device, direct(root_pos, 0), 3 * 32, &[0xff]);
+
+    let efs = EasyFileSystem::open(device.clone()).unwrap();
+    // the bad name is reported rather than trusted
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls().err(), Some(FsError::Corrupted));
+    assert_eq!(root_inode.find("missing").err(), Some(FsError::Corrupted));
+    let report = fsck::check(&efs, false).unwrap();
+    let expected = [
+        Problem::BadDirent { dir: 0, index: 3, name: String::new(), error: DirentError::BadName },
+        Problem::BadDirent {
+            dir: 0,
+            index: 6,
+            name: String::from("gone"),
+            error: DirentError::Unallocated,
+        },
+        Problem::DoubleAllocated { block: shared, inode: b, owner: a },
+        Problem::WrongLinkCount { inode: a, nlink: 5, found: 1 },
+        Problem::OrphanInode { inode: orphan_inode },
+        Problem::OrphanBlock { block: orphan_block },
+        Problem::OrphanBlock { block: lost },
+    ];
+    for problem in expected.iter() {
+        assert!(report.problems.contains(problem), "{:?} in {:?}", problem, report.problems);
+    }
+    assert!(!report.repaired);
+    assert!(!fsck::check(&efs, false).unwrap().is_clean());
+
+    // after the repair, the filesystem is clean and its bitmaps match
+    let report = fsck::check(&efs, true).unwrap();
+    assert!(report.repaired);
+    efs.lock().unmount().unwrap();
+    let efs = EasyFileSystem::open(device).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!(report.inodes, efs.lock().used_inodes().unwrap());
+    assert_eq!(report.blocks, efs.lock().used_data_blocks().unwrap());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.find("gone").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.find("dir/b").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.find("a").unwrap().stat().unwrap().nlink, 1);
+
+    // repairs of more inode blocks than the block cache holds
+    let device = MemBlockDevice::new(4096);
+    let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let inodes: Vec<u32> = (0..100)
+        .map(|i| root_inode.create(&format!("f{}", i)).unwrap().stat().unwrap().ino)
+        .collect();
+    let mut fs = efs.lock();
+    fs.unmount().unwrap();
+    let positions: Vec<_> = inodes.iter().map(|&inode| fs.get_disk_inode_pos(inode)).collect();
+    drop(fs);
+    let mut inode_blocks: Vec<u32> = positions.iter().map(|&(block_id, _)| block_id).collect();
+    inode_blocks.dedup();
+    assert!(inode_blocks.len() > 16);
+    for &(block_id, offset) in positions.iter() {
+        patch_block(&device, block_id, offset + 120, &3u32.to_le_bytes());
+    }
+    let efs = EasyFileSystem::open(device.clone()).unwrap();
+    let report = fsck::check(&efs, true).unwrap();
+    assert_eq!(report.problems.len(), inodes.len());
+    assert!(report.repaired);
+    efs.lock().unmount().unwrap();
+    let efs = EasyFileSystem::open(device).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!(report.inodes, inodes.len() + 1);
+}
@@ -2,6 +2,7 @@ use alloc::sync::Arc;
 use super::{
     BlockDevice,
     BLOCK_SZ,
+    FsResult,
     get_block_cache,
 };
 
@@ -9,7 +10,7 @@ use super::{
 type BitmapBlock = [u64; 64];
 
 /// Number of bits in a block
-const BLOCK_BITS: usize = BLOCK_SZ * 8;
+pub(crate) const BLOCK_BITS: usize = BLOCK_SZ * 8;
 
 /// A bitmap
 pub struct Bitmap {
@@ -32,13 +33,14 @@ impl Bitmap {
             blocks,
         }
     }
-    /// Allocate a new block from a block device
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+    /// Allocate a new block from a block device, return `None` if the
+    /// bitmap is full
+    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> FsResult<Option<usize>> {
         for block_id in 0..self.blocks {
             let pos = get_block_cache(
                 block_id + self.start_block_id as usize,
                 Arc::clone(block_device),
-            ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            )?.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
                 if let Some((bits64_pos, inner_pos)) = bitmap_block
                     .iter()
                     .enumerate()
@@ -54,21 +56,70 @@ impl Bitmap {
                 }
             });
             if pos.is_some() {
-                return pos;
+                return Ok(pos);
             }
         }
-        None
+        Ok(None)
     }
-    /// Deallocate a block
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+    /// Deallocate a block, return false if it was not allocated
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> FsResult<bool> {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
-        get_block_cache(
+        if block_pos >= self.blocks {
+            return Ok(false);
+        }
+        Ok(get_block_cache(
             block_pos + self.start_block_id,
             Arc::clone(block_device)
-        ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-            assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
+        )?.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            if bitmap_block[bits64_pos] & (1u64 << inner_pos) == 0 {
+                return false;
+            }
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
-        });
+            true
+        }))
+    }
+    /// Allocate a given bit, return false if it was allocated already
+    pub fn alloc_bit(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> FsResult<bool> {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        if block_pos >= self.blocks {
+            return Ok(false);
+        }
+        Ok(get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        )?.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            if bitmap_block[bits64_pos] & (1u64 << inner_pos) != 0 {
+                return false;
+            }
+            bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            true
+        }))
+    }
+    /// Whether a bit is allocated
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> FsResult<bool> {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        if block_pos >= self.blocks {
+            return Ok(false);
+        }
+        Ok(get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        )?.lock().read(0, |bitmap_block: &BitmapBlock| {
+            bitmap_block[bits64_pos] & (1u64 << inner_pos) != 0
+        }))
+    }
+    /// Get the number of allocated bits
+    pub fn allocated(&self, block_device: &Arc<dyn BlockDevice>) -> FsResult<usize> {
+        let mut allocated = 0;
+        for block_id in 0..self.blocks {
+            allocated += get_block_cache(
+                block_id + self.start_block_id,
+                Arc::clone(block_device),
+            )?.lock().read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block.iter().map(|bits64| bits64.count_ones() as usize).sum::<usize>()
+            });
+        }
+        Ok(allocated)
     }
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
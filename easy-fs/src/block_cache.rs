@@ -1,9 +1,13 @@
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    FsError,
+    FsResult,
 };
-use alloc::collections::VecDeque;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use lazy_static::*;
 use spin::Mutex;
 
@@ -17,6 +21,13 @@ pub struct BlockCache {
     block_device: Arc<dyn BlockDevice>,
     /// whether the block is dirty
     modified: bool,
+    /// whether the block is modified by the transaction which is not
+    /// committed yet, so it must not be written back
+    pending: bool,
+    /// The contents and whether the block was dirty before the pending
+    /// transaction modified it, which are restored if it is discarded,
+    /// `None` if the block was not cached then
+    committed: Option<(Box<[u8; BLOCK_SZ]>, bool)>,
 }
 
 impl BlockCache {
@@ -24,16 +35,50 @@ impl BlockCache {
     pub fn new(
         block_id: usize,
         block_device: Arc<dyn BlockDevice>
-    ) -> Self {
+    ) -> FsResult<Self> {
         let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
-        Self {
+        block_device.read_block(block_id, &mut cache)?;
+        Ok(Self {
             cache,
             block_id,
             block_device,
             modified: false,
+            pending: false,
+            committed: None,
+        })
+    }
+    /// A new BlockCache of zeroes, which replaces the block without
+    /// reading it
+    pub fn zeroed(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>
+    ) -> Self {
+        Self {
+            cache: [0u8; BLOCK_SZ],
+            block_id,
+            block_device,
+            modified: true,
+            pending: true,
+            committed: None,
+        }
+    }
+
+    /// Keep the committed contents before the transaction first modifies
+    /// the block
+    fn begin_modify(&mut self) {
+        if !self.pending {
+            self.committed = Some((Box::new(self.cache), self.modified));
+            self.pending = true;
         }
+        self.modified = true;
+    }
+
+    /// The transaction which modifies the block is committed
+    fn commit(&mut self) {
+        self.pending = false;
+        self.committed = None;
     }
+
     /// Get the address of an offset inside the cached block data
     fn addr_of_offset(&self, offset: usize) -> usize {
         &self.cache[offset] as *const _ as usize
@@ -49,7 +94,7 @@ impl BlockCache {
     pub fn get_mut<T>(&mut self, offset: usize) -> &mut T where T: Sized {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
-        self.modified = true;
+        self.begin_modify();
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -62,83 +107,472 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
-    pub fn sync(&mut self) {
-        if self.modified {
+    /// Write the block back if it is dirty, it stays dirty if the write fails
+    ///
+    /// A block modified by the transaction which is not committed yet is
+    /// not written back until then.
+    pub fn sync(&mut self) -> FsResult<()> {
+        if self.modified && !self.pending {
+            self.block_device.write_block(self.block_id, &self.cache)?;
             self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
         }
+        Ok(())
     }
 }
 
 impl Drop for BlockCache {
+    /// The cache manager syncs a block before dropping it, so this only
+    /// matters for a block dropped on failure, whose error is lost anyway
     fn drop(&mut self) {
-        self.sync()
+        let _ = self.sync();
     }
 }
 
-/// Use a block cache of 16 blocks
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Number of cached blocks unless it is set by [`set_block_cache_capacity`]
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 16;
+/// The filesystem lock keeps operations from overlapping, and a transaction
+/// keeps pending at most the disk inodes it changes, the index blocks of a
+/// write chunk, two partial data blocks and the bitmap blocks of the blocks
+/// it allocates or frees, which fit in this many blocks with room for those
+/// it reads meanwhile
+const MIN_BLOCK_CACHE_SIZE: usize = 16;
+/// End of the LRU list
+const NIL: usize = usize::MAX;
+
+/// Identify a block device by the address of the device, which is not
+/// reused as long as any of its blocks are cached
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+/// A cached block in the LRU list
+struct Node {
+    /// Device id and block id
+    key: (usize, usize),
+    cache: Arc<Mutex<BlockCache>>,
+    /// More recently used neighbour
+    prev: usize,
+    /// Less recently used neighbour
+    next: usize,
+}
 
+/// Block caches in least recently used order
+///
+/// Nodes live in a slab, and are linked from the most recently used one
+/// at `head` to the least recently used one at `tail`. The map finds the
+/// node of a block, by the device and the block id, so blocks of different
+/// devices do not mix up.
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    capacity: usize,
+    map: BTreeMap<(usize, usize), usize>,
+    nodes: Vec<Option<Node>>,
+    free_slots: Vec<usize>,
+    head: usize,
+    tail: usize,
 }
 
 impl BlockCacheManager {
-    pub fn new() -> Self {
-        Self { queue: VecDeque::new() }
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(MIN_BLOCK_CACHE_SIZE),
+            map: BTreeMap::new(),
+            nodes: Vec::new(),
+            free_slots: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn node(&self, slot: usize) -> &Node {
+        self.nodes[slot].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node {
+        self.nodes[slot].as_mut().unwrap()
+    }
+
+    /// Take a node out of the list
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = {
+            let node = self.node(slot);
+            (node.prev, node.next)
+        };
+        if prev == NIL {
+            self.head = next;
+        } else {
+            self.node_mut(prev).next = next;
+        }
+        if next == NIL {
+            self.tail = prev;
+        } else {
+            self.node_mut(next).prev = prev;
+        }
+    }
+
+    /// Put a node at the head of the list
+    fn push_front(&mut self, slot: usize) {
+        let head = self.head;
+        {
+            let node = self.node_mut(slot);
+            node.prev = NIL;
+            node.next = head;
+        }
+        if head == NIL {
+            self.tail = slot;
+        } else {
+            self.node_mut(head).prev = slot;
+        }
+        self.head = slot;
+    }
+
+    /// Drop a cached block, which is written back first if it is dirty,
+    /// and kept if that fails
+    fn remove(&mut self, slot: usize) -> FsResult<()> {
+        self.node(slot).cache.lock().sync()?;
+        self.unlink(slot);
+        let node = self.nodes[slot].take().unwrap();
+        self.map.remove(&node.key);
+        self.free_slots.push(slot);
+        Ok(())
+    }
+
+    /// Whether the block is used outside the cache
+    fn in_use(&self, slot: usize) -> bool {
+        Arc::strong_count(&self.node(slot).cache) > 1
+    }
+
+    /// Whether the block is modified by the transaction which is not
+    /// committed yet
+    fn is_pending(&self, slot: usize) -> bool {
+        self.node(slot).cache.lock().pending
     }
 
+    /// Evict the least recently used block which is neither in use nor
+    /// pending, return whether there is one
+    fn evict(&mut self) -> FsResult<bool> {
+        let mut slot = self.tail;
+        while slot != NIL {
+            if !self.in_use(slot) && !self.is_pending(slot) {
+                self.remove(slot)?;
+                return Ok(true);
+            }
+            slot = self.node(slot).prev;
+        }
+        Ok(false)
+    }
+
+    /// Move a cached block to the head of the list, and get its cache
+    fn touch(&mut self, key: (usize, usize)) -> Option<Arc<Mutex<BlockCache>>> {
+        let slot = *self.map.get(&key)?;
+        self.unlink(slot);
+        self.push_front(slot);
+        Some(Arc::clone(&self.node(slot).cache))
+    }
+
+    /// Evict blocks until there is room for one more
+    ///
+    /// Pending blocks are released only when the transaction which
+    /// modifies them commits, so if every cached block is in use or
+    /// pending, the operation needs more blocks than the cache holds.
+    fn make_room(&mut self) -> FsResult<()> {
+        while self.map.len() >= self.capacity {
+            if !self.evict()? {
+                return Err(FsError::TooManyBlocks);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the cache of a block, loading it if it is not cached
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue
-            .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
-        } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self.queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
+    ) -> FsResult<Arc<Mutex<BlockCache>>> {
+        let key = (device_id(&block_device), block_id);
+        if let Some(block_cache) = self.touch(key) {
+            return Ok(block_cache);
+        }
+        self.make_room()?;
+        // load block into mem and push front
+        let block_cache = BlockCache::new(block_id, block_device)?;
+        Ok(self.insert(key, block_cache))
+    }
+
+    /// Get the cache of a block filled with zeroes, without reading the
+    /// block
+    pub fn get_zeroed_block_cache(
+        &mut self,
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> FsResult<Arc<Mutex<BlockCache>>> {
+        let key = (device_id(&block_device), block_id);
+        if let Some(block_cache) = self.touch(key) {
+            let mut locked = block_cache.lock();
+            locked.begin_modify();
+            locked.cache.fill(0);
+            drop(locked);
+            return Ok(block_cache);
+        }
+        self.make_room()?;
+        Ok(self.insert(key, BlockCache::zeroed(block_id, block_device)))
+    }
+
+    /// Put a new block at the head of the list
+    fn insert(&mut self, key: (usize, usize), block_cache: BlockCache) -> Arc<Mutex<BlockCache>> {
+        let block_cache = Arc::new(Mutex::new(block_cache));
+        let node = Node {
+            key,
+            cache: Arc::clone(&block_cache),
+            prev: NIL,
+            next: NIL,
+        };
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.map.insert(key, slot);
+        self.push_front(slot);
+        block_cache
+    }
+
+    /// Slots of the cached blocks of a device in a range of block ids
+    fn slots_in(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        block_ids: core::ops::Range<usize>,
+    ) -> Vec<(usize, usize)> {
+        let device_id = device_id(block_device);
+        self.map
+            .range((device_id, block_ids.start)..(device_id, block_ids.end))
+            .map(|(&(_, block_id), &slot)| (block_id, slot))
+            .collect()
+    }
+
+    /// Read the contiguous blocks from `block_id` into `buf`, whose length
+    /// is a multiple of the block size
+    ///
+    /// Blocks which are not cached are read from the device in one request,
+    /// and are not cached, so a long sequential read does not flush the
+    /// cache. The cached ones may be newer than the device, and are copied
+    /// from the cache.
+    pub fn read_blocks(
+        &self,
+        block_id: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        buf: &mut [u8],
+    ) -> FsResult<()> {
+        let count = buf.len() / BLOCK_SZ;
+        let cached = self.slots_in(block_device, block_id..block_id + count);
+        if cached.len() < count {
+            block_device.read_blocks(block_id, buf)?;
+        }
+        for (id, slot) in cached {
+            let offset = (id - block_id) * BLOCK_SZ;
+            buf[offset..offset + BLOCK_SZ].copy_from_slice(&self.node(slot).cache.lock().cache);
+        }
+        Ok(())
+    }
+
+    /// Write `buf`, whose length is a multiple of the block size, into the
+    /// contiguous blocks from `block_id`
+    ///
+    /// The blocks are written to the device in one request, and the cached
+    /// ones are updated to match the device, so they are neither dirty nor
+    /// pending any more.
+    pub fn write_blocks(
+        &self,
+        block_id: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        buf: &[u8],
+    ) -> FsResult<()> {
+        let count = buf.len() / BLOCK_SZ;
+        block_device.write_blocks(block_id, buf)?;
+        for (id, slot) in self.slots_in(block_device, block_id..block_id + count) {
+            let offset = (id - block_id) * BLOCK_SZ;
+            let mut block_cache = self.node(slot).cache.lock();
+            block_cache.cache.copy_from_slice(&buf[offset..offset + BLOCK_SZ]);
+            block_cache.modified = false;
+            block_cache.commit();
+        }
+        Ok(())
+    }
+
+    /// Drop the cached copies of blocks without writing them back, as
+    /// their contents no longer matter
+    pub fn forget(&mut self, block_ids: core::ops::Range<usize>, block_device: &Arc<dyn BlockDevice>) {
+        for (_, slot) in self.slots_in(block_device, block_ids) {
+            let mut block_cache = self.node(slot).cache.lock();
+            block_cache.modified = false;
+            block_cache.commit();
+            drop(block_cache);
+            if !self.in_use(slot) {
+                // a clean block is removed without any I/O
+                let _ = self.remove(slot);
+            }
+        }
+    }
+
+    /// Change the number of cached blocks, evicting blocks which are not
+    /// in use if there are too many, the others, and those which fail to
+    /// be written back, are evicted later
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(MIN_BLOCK_CACHE_SIZE);
+        while self.map.len() > self.capacity && matches!(self.evict(), Ok(true)) {}
+    }
+
+    /// Copy the pending blocks of a device, in the order of block ids
+    pub fn pending_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<(usize, [u8; BLOCK_SZ])> {
+        self.slots_in(block_device, 0..usize::MAX)
+            .into_iter()
+            .filter_map(|(block_id, slot)| {
+                let block_cache = self.node(slot).cache.lock();
+                block_cache.pending.then_some((block_id, block_cache.cache))
+            })
+            .collect()
+    }
+
+    /// Mark the pending blocks of a device as committed, they are written
+    /// back like other dirty blocks from now on
+    pub fn clear_pending(&self, block_device: &Arc<dyn BlockDevice>) {
+        for (_, slot) in self.slots_in(block_device, 0..usize::MAX) {
+            self.node(slot).cache.lock().commit();
+        }
+    }
+
+    /// Undo the changes of the pending blocks of a device, which return to
+    /// their committed contents, and those which were not cached before
+    /// the transaction are dropped to be read again
+    pub fn discard_pending(&mut self, block_device: &Arc<dyn BlockDevice>) {
+        let mut uncached = Vec::new();
+        for (block_id, slot) in self.slots_in(block_device, 0..usize::MAX) {
+            let mut block_cache = self.node(slot).cache.lock();
+            if !block_cache.pending {
+                continue;
+            }
+            match block_cache.committed.take() {
+                Some((cache, modified)) => {
+                    block_cache.cache = *cache;
+                    block_cache.modified = modified;
+                    block_cache.pending = false;
                 }
+                None => uncached.push(block_id),
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(
-                BlockCache::new(block_id, Arc::clone(&block_device))
-            ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+        }
+        for block_id in uncached {
+            self.forget(block_id..block_id + 1, block_device);
         }
     }
+
+    /// Write the dirty blocks of a device back, and flush the device
+    pub fn sync_device(&self, block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+        for (_, slot) in self.slots_in(block_device, 0..usize::MAX) {
+            self.node(slot).cache.lock().sync()?;
+        }
+        block_device.flush()
+    }
+
+    /// Write back and drop the cached blocks of a device, except those in
+    /// use or pending, which are dropped when they are evicted later, and
+    /// flush the device
+    pub fn drop_device(&mut self, block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+        for (_, slot) in self.slots_in(block_device, 0..usize::MAX) {
+            if !self.in_use(slot) && !self.is_pending(slot) {
+                self.remove(slot)?;
+            }
+        }
+        block_device.flush()
+    }
 }
 
 lazy_static! {
     /// The global block cache manager
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(
-        BlockCacheManager::new()
+        BlockCacheManager::new(DEFAULT_BLOCK_CACHE_SIZE)
     );
 }
 
 /// Get the block cache corresponding to the given block id and block device
+///
+/// Fail with [`FsError::TooManyBlocks`] if every cached block is in use or
+/// pending.
 pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>
-) -> Arc<Mutex<BlockCache>> {
+) -> FsResult<Arc<Mutex<BlockCache>>> {
     BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
 }
 
-/// Sync all block cache to block device
-pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
-    }
+/// Get the block cache of a block filled with zeroes, without reading it
+/// from the block device
+pub fn get_zeroed_block_cache(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>
+) -> FsResult<Arc<Mutex<BlockCache>>> {
+    BLOCK_CACHE_MANAGER.lock().get_zeroed_block_cache(block_id, block_device)
+}
+
+/// Read contiguous blocks of a block device through the block cache
+pub fn block_cache_read_blocks(
+    block_id: usize,
+    block_device: &Arc<dyn BlockDevice>,
+    buf: &mut [u8],
+) -> FsResult<()> {
+    BLOCK_CACHE_MANAGER.lock().read_blocks(block_id, block_device, buf)
+}
+
+/// Write contiguous blocks of a block device through the block cache
+pub fn block_cache_write_blocks(
+    block_id: usize,
+    block_device: &Arc<dyn BlockDevice>,
+    buf: &[u8],
+) -> FsResult<()> {
+    BLOCK_CACHE_MANAGER.lock().write_blocks(block_id, block_device, buf)
+}
+
+/// Drop the cached copies of freed blocks, and discard them on the block
+/// device
+pub fn block_cache_discard(
+    block_id: usize,
+    count: usize,
+    block_device: &Arc<dyn BlockDevice>,
+) -> FsResult<()> {
+    BLOCK_CACHE_MANAGER.lock().forget(block_id..block_id + count, block_device);
+    block_device.discard(block_id, count)
+}
+
+/// Copy the blocks of a block device modified since the last commit
+pub fn block_cache_pending_blocks(block_device: &Arc<dyn BlockDevice>) -> Vec<(usize, [u8; BLOCK_SZ])> {
+    BLOCK_CACHE_MANAGER.lock().pending_blocks(block_device)
+}
+
+/// Commit the modified blocks of a block device
+pub fn block_cache_clear_pending(block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER.lock().clear_pending(block_device);
+}
+
+/// Undo the modified blocks of a block device since the last commit
+pub fn block_cache_discard_pending(block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER.lock().discard_pending(block_device);
+}
+
+/// Sync the block cache of a block device
+pub fn block_cache_sync_device(block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+    BLOCK_CACHE_MANAGER.lock().sync_device(block_device)
+}
+
+/// Sync and drop the block cache of a block device
+pub fn block_cache_drop_device(block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+    BLOCK_CACHE_MANAGER.lock().drop_device(block_device)
+}
+
+/// Set the number of cached blocks, which is at least 16
+pub fn set_block_cache_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity);
 }
@@ -1,8 +1,46 @@
 use core::any::Any;
+use super::{
+    BLOCK_SZ,
+    FsResult,
+};
 
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
+///
+/// A device reports its failures as [`crate::FsError::Io`]. Only
+/// `read_block` and `write_block` are required, the other methods fall back
+/// to them, or do nothing.
 pub trait BlockDevice : Send + Sync + Any {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    /// Read a block into `buf` of a block size
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()>;
+    /// Write `buf` of a block size into a block
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> FsResult<()>;
+    /// Read the contiguous blocks from `block_id` into `buf`, whose length
+    /// is a multiple of the block size
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        for (i, block) in buf.chunks_exact_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id + i, block)?;
+        }
+        Ok(())
+    }
+    /// Write `buf`, whose length is a multiple of the block size, into the
+    /// contiguous blocks from `block_id`
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        for (i, block) in buf.chunks_exact(BLOCK_SZ).enumerate() {
+            self.write_block(block_id + i, block)?;
+        }
+        Ok(())
+    }
+    /// Make the written blocks persistent
+    fn flush(&self) -> FsResult<()> {
+        Ok(())
+    }
+    /// Hint that `count` blocks from `block_id` are free, whose contents
+    /// are undefined afterwards
+    ///
+    /// The filesystem zeroes or overwrites a block when it allocates the
+    /// block, so by default nothing is done.
+    fn discard(&self, _block_id: usize, _count: usize) -> FsResult<()> {
+        Ok(())
+    }
 }
@@ -1,4 +1,6 @@
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use spin::Mutex;
 use super::{
     BlockDevice,
@@ -7,11 +9,41 @@ use super::{
     DiskInode,
     DiskInodeType,
     Inode,
+    Journal,
+    FsError,
+    FsResult,
+    MIN_JOURNAL_BLOCKS,
     get_block_cache,
-    block_cache_sync_all,
+    block_cache_write_blocks,
+    block_cache_discard,
+    block_cache_clear_pending,
+    block_cache_discard_pending,
+    block_cache_sync_device,
+    block_cache_drop_device,
 };
 use crate::BLOCK_SZ;
 
+/// The max number of journal blocks, a journal takes at most an eighth of
+/// the blocks after the inode area, and there is no journal if that is
+/// fewer than [`MIN_JOURNAL_BLOCKS`]
+const JOURNAL_BLOCKS: u32 = 1024;
+/// The max number of blocks zeroed by a request when a filesystem is
+/// created
+const ZERO_BATCH_BLOCKS: usize = 64;
+
+/// Write zeroes to the blocks `block_ids`
+fn zero_blocks(
+    block_ids: core::ops::Range<usize>,
+    block_device: &Arc<dyn BlockDevice>,
+) -> FsResult<()> {
+    let zeroes = vec![0u8; ZERO_BATCH_BLOCKS * BLOCK_SZ];
+    for start in block_ids.clone().step_by(ZERO_BATCH_BLOCKS) {
+        let blocks = ZERO_BATCH_BLOCKS.min(block_ids.end - start);
+        block_cache_write_blocks(start, block_device, &zeroes[..blocks * BLOCK_SZ])?;
+    }
+    Ok(())
+}
+
 /// An easy fs over a block device
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
@@ -19,51 +51,77 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    data_area_blocks: u32,
+    /// The journal of metadata blocks, if the filesystem has one
+    journal: Option<Journal>,
+    /// Data blocks freed by the transaction which is not committed yet,
+    /// which are discarded after it commits
+    freed_blocks: Vec<u32>,
 }
 
-/// A data block of block size
-type DataBlock = [u8; BLOCK_SZ];
-
 impl EasyFileSystem {
     /// Create a filesystem from a block device
+    ///
+    /// Fail with [`FsError::NoSpace`] if the device is too small for the
+    /// inode area and at least one data block. The last blocks of the
+    /// device are taken by the journal if it is large enough for one.
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
+    ) -> FsResult<Arc<Mutex<Self>>> {
         // calculate block size of areas & create bitmaps
         let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
             ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+        let mut data_total_blocks = total_blocks
+            .checked_sub(1 + inode_total_blocks)
+            .filter(|&blocks| blocks >= 2)
+            .ok_or(FsError::NoSpace)?;
+        let journal_blocks = match JOURNAL_BLOCKS.min(data_total_blocks / 8) {
+            blocks if blocks >= MIN_JOURNAL_BLOCKS => blocks,
+            _ => 0,
+        };
+        data_total_blocks -= journal_blocks;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
             data_bitmap_blocks as usize,
         );
+        let data_area_start_block = 1 + inode_total_blocks + data_bitmap_blocks;
+        let journal_start_block = total_blocks - journal_blocks;
+        // clear the metadata and the journal, data blocks are zeroed when
+        // they are allocated
+        zero_blocks(0..data_area_start_block as usize, &block_device)?;
+        block_cache_discard(
+            data_area_start_block as usize,
+            data_area_blocks as usize,
+            &block_device,
+        )?;
+        zero_blocks(journal_start_block as usize..total_blocks as usize, &block_device)?;
+        let journal = match journal_blocks {
+            0 => None,
+            _ => Some(Journal::format(
+                Arc::clone(&block_device),
+                journal_start_block,
+                journal_blocks,
+            )?),
+        };
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            data_area_start_block,
+            data_area_blocks,
+            journal,
+            freed_blocks: Vec::new(),
         };
-        // clear all blocks
-        for i in 0..total_blocks {
-            get_block_cache(
-                i as usize,
-                Arc::clone(&block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                for byte in data_block.iter_mut() { *byte = 0; }
-            });
-        }
         // initialize SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+        get_block_cache(0, Arc::clone(&block_device))?
         .lock()
         .modify(0, |super_block: &mut SuperBlock| {
             super_block.initialize(
@@ -72,34 +130,50 @@ impl EasyFileSystem {
                 inode_area_blocks,
                 data_bitmap_blocks,
                 data_area_blocks,
+                journal_blocks,
             );
         });
-        // write back immediately
         // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        let root_inode_id = efs.alloc_inode()?;
+        debug_assert_eq!(root_inode_id, 0);
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
         get_block_cache(
             root_inode_block_id as usize,
             Arc::clone(&block_device)
-        )
+        )?
         .lock()
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
             disk_inode.initialize(DiskInodeType::Directory);
         });
-        block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+        // the parent of the root is itself
+        let efs = Arc::new(Mutex::new(efs));
+        let root_inode = Self::root_inode(&efs);
+        root_inode.add_dirent(".", 0, &mut efs.lock())?;
+        root_inode.add_dirent("..", 0, &mut efs.lock())?;
+        efs.lock().sync()?;
+        Ok(efs)
     }
-    /// Open a block device as a filesystem
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+    /// Open a block device as a filesystem, and replay its journal
+    ///
+    /// Fail with [`FsError::Corrupted`] if there is no valid easy-fs on it.
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> FsResult<Arc<Mutex<Self>>> {
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+        let (mut efs, journal_start_block, journal_blocks) = get_block_cache(0, Arc::clone(&block_device))?
             .lock()
             .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
+                if !super_block.is_valid() {
+                    return Err(FsError::Corrupted);
+                }
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
+                let data_area_start_block =
+                    1 + inode_total_blocks + super_block.data_bitmap_blocks;
+                let journal_start_block = super_block.total_blocks
+                    .checked_sub(super_block.journal_blocks)
+                    .filter(|&start| start >= data_area_start_block + super_block.data_area_blocks)
+                    .ok_or(FsError::Corrupted)?;
                 let efs = Self {
-                    block_device,
+                    block_device: Arc::clone(&block_device),
                     inode_bitmap: Bitmap::new(
                         1,
                         super_block.inode_bitmap_blocks as usize
@@ -109,10 +183,18 @@ impl EasyFileSystem {
                         super_block.data_bitmap_blocks as usize,
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    data_area_start_block,
+                    data_area_blocks: super_block.data_area_blocks,
+                    journal: None,
+                    freed_blocks: Vec::new(),
                 };
-                Arc::new(Mutex::new(efs))
-            })
+                Ok((efs, journal_start_block, super_block.journal_blocks))
+            })?;
+        // the super block is not locked while the journal writes blocks home
+        if journal_blocks > 0 {
+            efs.journal = Some(Journal::replay(block_device, journal_start_block, journal_blocks)?);
+        }
+        Ok(Arc::new(Mutex::new(efs)))
     }
     /// Get the root inode of the filesystem
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
@@ -127,6 +209,53 @@ impl EasyFileSystem {
             block_device,
         )
     }
+    /// Commit the blocks modified since the last commit as a transaction,
+    /// and discard the data blocks it frees
+    ///
+    /// Without a journal the blocks are only released to be written back.
+    pub fn commit(&mut self) -> FsResult<()> {
+        match self.journal.as_mut() {
+            Some(journal) => {
+                journal.commit()?;
+                // replaying the journal must not overwrite a freed block
+                // after it is allocated again
+                if self.freed_blocks.iter().any(|&block_id| journal.is_logged(block_id)) {
+                    journal.checkpoint()?;
+                }
+            }
+            None => block_cache_clear_pending(&self.block_device),
+        }
+        for block_id in self.freed_blocks.drain(..) {
+            block_cache_discard(block_id as usize, 1, &self.block_device)?;
+        }
+        Ok(())
+    }
+    /// Undo the blocks modified since the last commit, for a failed
+    /// operation whose changes must not be committed
+    pub fn rollback(&mut self) {
+        block_cache_discard_pending(&self.block_device);
+        self.freed_blocks.clear();
+    }
+    /// Write all the cached changes back to the disk
+    pub fn sync(&mut self) -> FsResult<()> {
+        self.commit()?;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.checkpoint()?;
+        }
+        block_cache_sync_device(&self.block_device)
+    }
+    /// Write all the cached changes back to the disk, and drop the cached
+    /// blocks of the disk
+    ///
+    /// No inode of the filesystem should be used afterwards, unless the
+    /// filesystem is opened again.
+    pub fn unmount(&mut self) -> FsResult<()> {
+        self.commit()?;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.checkpoint()?;
+        }
+        block_cache_drop_device(&self.block_device)
+    }
     /// Get inode by id
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
@@ -134,31 +263,67 @@ impl EasyFileSystem {
         let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (block_id, (inode_id % inodes_per_block) as usize * inode_size)
     }
+    /// Get inode id by the position of the disk inode
+    pub fn get_inode_id(&self, block_id: u32, block_offset: usize) -> u32 {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        (block_id - self.inode_area_start_block) * inodes_per_block
+            + (block_offset / inode_size) as u32
+    }
     /// Get data block by id
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
     }
+    /// Block ids of the data area
+    pub(crate) fn data_area(&self) -> core::ops::Range<u32> {
+        self.data_area_start_block..self.data_area_start_block + self.data_area_blocks
+    }
     /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    pub fn alloc_inode(&mut self) -> FsResult<u32> {
+        self.inode_bitmap
+            .alloc(&self.block_device)?
+            .map(|inode_id| inode_id as u32)
+            .ok_or(FsError::NoSpace)
     }
-    /// Allocate a data block
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// Deallocate an inode
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> FsResult<()> {
+        if self.inode_bitmap.dealloc(&self.block_device, inode_id as usize)? {
+            Ok(())
+        } else {
+            Err(FsError::Corrupted)
+        }
     }
-    /// Deallocate a data block
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(
-            block_id as usize,
-            Arc::clone(&self.block_device)
-        )
-        .lock()
-        .modify(0, |data_block: &mut DataBlock| {
-            data_block.iter_mut().for_each(|p| { *p = 0; })
-        });
-        self.data_bitmap.dealloc(
-            &self.block_device,
-            (block_id - self.data_area_start_block) as usize
-        )
+    /// Allocate a data block, whose stale contents the caller overwrites
+    pub fn alloc_data(&mut self) -> FsResult<u32> {
+        let data_block_id = self.data_bitmap.alloc(&self.block_device)?.ok_or(FsError::NoSpace)?;
+        // the last bitmap block may cover more bits than the data area
+        if data_block_id >= self.data_area_blocks as usize {
+            self.data_bitmap.dealloc(&self.block_device, data_block_id)?;
+            return Err(FsError::NoSpace);
+        }
+        let block_id = data_block_id as u32 + self.data_area_start_block;
+        self.freed_blocks.retain(|&freed| freed != block_id);
+        Ok(block_id)
+    }
+    /// Deallocate a data block, whose contents are discarded after the
+    /// transaction commits
+    pub fn dealloc_data(&mut self, block_id: u32) -> FsResult<()> {
+        let data_block_id = block_id
+            .checked_sub(self.data_area_start_block)
+            .filter(|&id| id < self.data_area_blocks)
+            .ok_or(FsError::Corrupted)?;
+        if !self.data_bitmap.dealloc(&self.block_device, data_block_id as usize)? {
+            return Err(FsError::Corrupted);
+        }
+        self.freed_blocks.push(block_id);
+        Ok(())
+    }
+    /// Get the number of allocated inodes
+    pub fn used_inodes(&self) -> FsResult<usize> {
+        self.inode_bitmap.allocated(&self.block_device)
+    }
+    /// Get the number of allocated data blocks
+    pub fn used_data_blocks(&self) -> FsResult<usize> {
+        self.data_bitmap.allocated(&self.block_device)
     }
 }
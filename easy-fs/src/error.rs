@@ -0,0 +1,30 @@
+/// Errors of filesystem operations
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsError {
+    /// No free inode or data block is left
+    NoSpace,
+    /// A component of the path does not exist
+    NotFound,
+    /// A component of the path is not a directory
+    NotDir,
+    /// A directory is given where a file is required
+    IsDir,
+    /// The path already exists
+    Exists,
+    /// A component of the path is longer than the limit of a directory entry
+    NameTooLong,
+    /// A directory to remove is not empty
+    NotEmpty,
+    /// The operation does not apply to the path, like removing `.` or `/`
+    Invalid,
+    /// The on-disk structures are inconsistent
+    Corrupted,
+    /// The block device fails
+    Io,
+    /// An operation needs more blocks at once than the block cache holds,
+    /// or modifies more than a journal transaction commits
+    TooManyBlocks,
+}
+
+/// Result of filesystem operations
+pub type FsResult<T> = Result<T, FsError>;
@@ -0,0 +1,420 @@
+use super::{
+    BlockDevice,
+    DiskInode,
+    DiskInodeType,
+    DirEntry,
+    EasyFileSystem,
+    FsError,
+    FsResult,
+    Inode,
+    BLOCK_BITS,
+    DIRENT_SZ,
+    get_block_cache,
+};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+
+/// Why a directory entry is bad
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirentError {
+    /// The name is not valid UTF-8, or it is empty or has a `/`
+    BadName,
+    /// An earlier entry of the directory has the same name
+    Duplicate,
+    /// The inode number is beyond the inode area
+    BadInodeNumber,
+    /// The inode is free in the inode bitmap
+    Unallocated,
+    /// The inode is bad, or shares blocks with another inode
+    BadInode,
+    /// The directory is already linked from another entry
+    DirectoryLinked,
+    /// `.` or `..` refers to a wrong directory
+    WrongTarget,
+}
+
+/// A problem found by [`check`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The root inode is not a valid directory, nothing else is checked
+    BadRoot,
+    /// The type of an inode is invalid, or its blocks are outside the data
+    /// area or do not match its size
+    BadInode { inode: u32 },
+    /// A block of `inode` is already used by `owner`, which may be `inode`
+    /// itself
+    DoubleAllocated { block: u32, inode: u32, owner: u32 },
+    /// The `index`-th entry of directory `dir` is bad, its name is empty if
+    /// it is not valid
+    BadDirent { dir: u32, index: usize, name: String, error: DirentError },
+    /// Directory `dir` has no `.` or `..`
+    MissingDirent { dir: u32, name: &'static str },
+    /// The link count of an inode differs from the number of entries which
+    /// refer to it
+    WrongLinkCount { inode: u32, nlink: u32, found: u32 },
+    /// A reachable inode is free in the inode bitmap
+    UnmarkedInode { inode: u32 },
+    /// An allocated inode is not reachable from the root
+    OrphanInode { inode: u32 },
+    /// A block of a reachable inode is free in the data bitmap
+    UnmarkedBlock { block: u32 },
+    /// An allocated data block is not used by any reachable inode
+    OrphanBlock { block: u32 },
+}
+
+/// Result of [`check`]
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of inodes reachable from the root
+    pub inodes: usize,
+    /// Number of data blocks of the reachable inodes, including indirect
+    /// blocks
+    pub blocks: usize,
+    pub problems: Vec<Problem>,
+    /// Whether the problems are repaired
+    pub repaired: bool,
+}
+
+impl Report {
+    /// Whether no problem is found
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check a filesystem, and repair it if `repair` is set
+///
+/// Directories are walked from the root, and every reachable inode is
+/// checked against its blocks, the link count and the bitmaps. A bad entry
+/// is removed, or corrected if it is a `.` or `..` to a wrong directory. The
+/// link counts and the bitmaps are set to match what is reachable after
+/// that, so an orphan inode or block is freed rather than recovered.
+///
+/// The repairs are committed a few blocks at a time, one entry, inode or
+/// bitmap block per transaction, so that any number of them fit in the
+/// block cache. If the check fails, the repairs not committed yet are
+/// undone.
+pub fn check(efs: &Arc<Mutex<EasyFileSystem>>, repair: bool) -> FsResult<Report> {
+    let fs = efs.lock();
+    let block_device = Arc::clone(&fs.block_device);
+    let mut checker = Checker {
+        efs,
+        fs,
+        block_device,
+        repair,
+        links: BTreeMap::new(),
+        owners: BTreeMap::new(),
+        bad: BTreeSet::new(),
+        dirs: Vec::new(),
+        missing: Vec::new(),
+        report: Report::default(),
+    };
+    if let Err(err) = checker.run() {
+        checker.fs.rollback();
+        return Err(err);
+    }
+    checker.report.inodes = checker.links.len();
+    checker.report.blocks = checker.owners.len();
+    Ok(checker.report)
+}
+
+struct Checker<'a> {
+    efs: &'a Arc<Mutex<EasyFileSystem>>,
+    fs: MutexGuard<'a, EasyFileSystem>,
+    block_device: Arc<dyn BlockDevice>,
+    repair: bool,
+    /// Number of entries referring to each reachable inode
+    links: BTreeMap<u32, u32>,
+    /// Owner of each block of the reachable inodes
+    owners: BTreeMap<u32, u32>,
+    /// Inodes found bad
+    bad: BTreeSet<u32>,
+    /// Directories to walk, with their parents
+    dirs: Vec<(u32, u32)>,
+    /// Missing `.` and `..` of directories with their targets, which are
+    /// added after the bitmaps are repaired, as they may take a block
+    missing: Vec<(u32, &'static str, u32)>,
+    report: Report,
+}
+
+impl<'a> Checker<'a> {
+    /// Check the filesystem, repairing the problems as they are found if
+    /// `repair` is set
+    fn run(&mut self) -> FsResult<()> {
+        if self.walk()? {
+            self.check_links()?;
+            self.check_bitmaps()?;
+            self.add_missing()?;
+            if self.repair && !self.report.is_clean() {
+                self.fs.sync()?;
+                self.report.repaired = true;
+            }
+        }
+        Ok(())
+    }
+    fn read_disk_inode<V>(
+        &self,
+        inode: u32,
+        f: impl FnOnce(&DiskInode) -> FsResult<V>,
+    ) -> FsResult<V> {
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(block_offset, f)
+    }
+    fn modify_disk_inode<V>(
+        &self,
+        inode: u32,
+        f: impl FnOnce(&mut DiskInode) -> FsResult<V>,
+    ) -> FsResult<V> {
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(block_offset, f)
+    }
+    fn is_dir(&self, inode: u32) -> FsResult<bool> {
+        self.read_disk_inode(inode, |disk_inode| {
+            Ok(disk_inode.type_() == Ok(DiskInodeType::Directory))
+        })
+    }
+    /// Check an inode reached for the first time, and take its blocks if
+    /// it is good
+    fn check_inode(&mut self, inode: u32) -> FsResult<bool> {
+        let data_area = self.fs.data_area();
+        let blocks = self.read_disk_inode(inode, |disk_inode| {
+            let is_dir = match disk_inode.type_() {
+                Ok(type_) => type_ == DiskInodeType::Directory,
+                Err(_) => return Ok(None),
+            };
+            if is_dir && disk_inode.size as usize % DIRENT_SZ != 0 {
+                return Ok(None);
+            }
+            disk_inode.blocks(|block_id| data_area.contains(&block_id), &self.block_device)
+        })?;
+        let blocks = match blocks {
+            Some(blocks) => blocks,
+            None => {
+                self.report.problems.push(Problem::BadInode { inode });
+                self.bad.insert(inode);
+                return Ok(false);
+            }
+        };
+        let mut own = BTreeSet::new();
+        for &block in blocks.iter() {
+            let owner = match self.owners.get(&block) {
+                Some(&owner) => owner,
+                None if !own.insert(block) => inode,
+                None => continue,
+            };
+            self.report.problems.push(Problem::DoubleAllocated { block, inode, owner });
+            self.bad.insert(inode);
+            return Ok(false);
+        }
+        self.owners.extend(blocks.into_iter().map(|block| (block, inode)));
+        Ok(true)
+    }
+    /// Check the inode of a directory entry other than `.` and `..`, and
+    /// count the link if the entry is good
+    fn check_child(&mut self, inode: u32) -> FsResult<Option<DirentError>> {
+        if inode as usize >= self.fs.inode_bitmap.maximum() {
+            return Ok(Some(DirentError::BadInodeNumber));
+        }
+        if !self.fs.inode_bitmap.is_allocated(&self.block_device, inode as usize)? {
+            return Ok(Some(DirentError::Unallocated));
+        }
+        if self.bad.contains(&inode) {
+            return Ok(Some(DirentError::BadInode));
+        }
+        if self.links.contains_key(&inode) {
+            if self.is_dir(inode)? {
+                return Ok(Some(DirentError::DirectoryLinked));
+            }
+        } else if !self.check_inode(inode)? {
+            return Ok(Some(DirentError::BadInode));
+        }
+        *self.links.entry(inode).or_insert(0) += 1;
+        Ok(None)
+    }
+    /// Walk the directories from the root, return false if the root is bad
+    fn walk(&mut self) -> FsResult<bool> {
+        if !self.check_inode(0)? || !self.is_dir(0)? {
+            self.report.problems.push(Problem::BadRoot);
+            return Ok(false);
+        }
+        self.links.insert(0, 0);
+        self.dirs.push((0, 0));
+        while let Some((dir, parent)) = self.dirs.pop() {
+            self.check_dir(dir, parent)?;
+        }
+        Ok(true)
+    }
+    /// Check the entries of a directory, whose parent is `parent`
+    fn check_dir(&mut self, dir: u32, parent: u32) -> FsResult<()> {
+        let block_device = Arc::clone(&self.block_device);
+        let dirents = self.read_disk_inode(dir, |disk_inode| {
+            let mut buf = vec![0u8; disk_inode.size as usize];
+            disk_inode.read_at(0, &mut buf, &block_device)?;
+            Ok(buf.chunks_exact(DIRENT_SZ).map(|bytes| {
+                let mut dirent = DirEntry::empty();
+                dirent.as_bytes_mut().copy_from_slice(bytes);
+                dirent
+            }).collect::<Vec<_>>())
+        })?;
+        let mut names = BTreeSet::new();
+        for (index, dirent) in dirents.iter().enumerate() {
+            if dirent.is_empty() {
+                continue;
+            }
+            let name = dirent.checked_name();
+            let target = match name {
+                Some(".") => Some(dir),
+                Some("..") => Some(parent),
+                _ => None,
+            };
+            let error = match (name, target) {
+                (None, _) => Some(DirentError::BadName),
+                (Some(name), _) if !names.insert(name) => Some(DirentError::Duplicate),
+                (_, Some(target)) => {
+                    // counted as it is after the repair
+                    *self.links.entry(target).or_insert(0) += 1;
+                    (target != dirent.inode_number()).then_some(DirentError::WrongTarget)
+                }
+                (_, None) => self.check_child(dirent.inode_number())?,
+            };
+            // walk a directory when it is first linked
+            if error.is_none() && target.is_none() {
+                let inode = dirent.inode_number();
+                if self.links[&inode] == 1 && self.is_dir(inode)? {
+                    self.dirs.push((inode, dir));
+                }
+            }
+            let error = match error {
+                Some(error) => error,
+                None => continue,
+            };
+            self.report.problems.push(Problem::BadDirent {
+                dir,
+                index,
+                name: name.unwrap_or_default().to_string(),
+                error,
+            });
+            if self.repair {
+                let repaired = match (error, name, target) {
+                    (DirentError::WrongTarget, Some(name), Some(target)) => {
+                        DirEntry::new(name, target)
+                    }
+                    _ => DirEntry::empty(),
+                };
+                self.modify_disk_inode(dir, |disk_inode| {
+                    disk_inode.write_at(
+                        index * DIRENT_SZ,
+                        repaired.as_bytes(),
+                        // a bad entry is not in a hole
+                        &mut || Err(FsError::Corrupted),
+                        &block_device,
+                    )
+                })?;
+                self.fs.commit()?;
+            }
+        }
+        for (name, target) in [(".", dir), ("..", parent)] {
+            if !names.contains(name) {
+                self.report.problems.push(Problem::MissingDirent { dir, name });
+                *self.links.entry(target).or_insert(0) += 1;
+                self.missing.push((dir, name, target));
+            }
+        }
+        Ok(())
+    }
+    /// Check the link counts of the reachable inodes
+    fn check_links(&mut self) -> FsResult<()> {
+        for (&inode, &found) in self.links.iter() {
+            let nlink = self.read_disk_inode(inode, |disk_inode| Ok(disk_inode.nlink))?;
+            if nlink == found {
+                continue;
+            }
+            self.report.problems.push(Problem::WrongLinkCount { inode, nlink, found });
+            if self.repair {
+                self.modify_disk_inode(inode, |disk_inode| {
+                    disk_inode.nlink = found;
+                    Ok(())
+                })?;
+                self.fs.commit()?;
+            }
+        }
+        Ok(())
+    }
+    /// Check the bitmaps against the reachable inodes and their blocks
+    fn check_bitmaps(&mut self) -> FsResult<()> {
+        let block_device = Arc::clone(&self.block_device);
+        for inode in 0..self.fs.inode_bitmap.maximum() {
+            // a transaction for each bitmap block
+            if inode % BLOCK_BITS == 0 {
+                self.fs.commit()?;
+            }
+            let inode_bitmap = &self.fs.inode_bitmap;
+            let reachable = self.links.contains_key(&(inode as u32));
+            if inode_bitmap.is_allocated(&block_device, inode)? == reachable {
+                continue;
+            }
+            let inode_id = inode as u32;
+            if reachable {
+                self.report.problems.push(Problem::UnmarkedInode { inode: inode_id });
+                if self.repair {
+                    inode_bitmap.alloc_bit(&block_device, inode)?;
+                }
+            } else {
+                self.report.problems.push(Problem::OrphanInode { inode: inode_id });
+                if self.repair {
+                    inode_bitmap.dealloc(&block_device, inode)?;
+                }
+            }
+        }
+        let data_area = self.fs.data_area();
+        for bit in 0..self.fs.data_bitmap.maximum() {
+            if bit % BLOCK_BITS == 0 {
+                self.fs.commit()?;
+            }
+            let data_bitmap = &self.fs.data_bitmap;
+            let block = data_area.start + bit as u32;
+            let used = self.owners.contains_key(&block);
+            if data_bitmap.is_allocated(&block_device, bit)? == used {
+                continue;
+            }
+            if used {
+                self.report.problems.push(Problem::UnmarkedBlock { block });
+                if self.repair {
+                    data_bitmap.alloc_bit(&block_device, bit)?;
+                }
+            } else {
+                self.report.problems.push(Problem::OrphanBlock { block });
+                if self.repair {
+                    data_bitmap.dealloc(&block_device, bit)?;
+                }
+            }
+        }
+        self.fs.commit()
+    }
+    /// Add the missing `.` and `..`
+    fn add_missing(&mut self) -> FsResult<()> {
+        if !self.repair {
+            return Ok(());
+        }
+        for (dir, name, target) in core::mem::take(&mut self.missing) {
+            let (block_id, block_offset) = self.fs.get_disk_inode_pos(dir);
+            let inode = Inode::new(
+                block_id,
+                block_offset,
+                Arc::clone(self.efs),
+                Arc::clone(&self.block_device),
+            );
+            inode.add_dirent(name, target, &mut self.fs)?;
+            self.fs.commit()?;
+        }
+        Ok(())
+    }
+}
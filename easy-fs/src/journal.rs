@@ -0,0 +1,209 @@
+use super::{
+    BLOCK_SZ,
+    BlockDevice,
+    FsError,
+    FsResult,
+    JournalHeader,
+    JournalDescriptor,
+    JournalCommit,
+    JOURNAL_DESCRIPTOR_IDS,
+    block_cache_read_blocks,
+    block_cache_write_blocks,
+    block_cache_pending_blocks,
+    block_cache_clear_pending,
+    block_cache_sync_device,
+};
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// The max number of blocks committed atomically by a transaction
+pub const MAX_TRANSACTION_BLOCKS: usize = JOURNAL_DESCRIPTOR_IDS;
+/// The smallest journal, which holds the header and the largest
+/// transaction with its descriptor and commit block
+pub const MIN_JOURNAL_BLOCKS: u32 = MAX_TRANSACTION_BLOCKS as u32 + 3;
+
+/// Serialize a journal block
+fn to_block<T: Copy>(value: &T) -> [u8; BLOCK_SZ] {
+    assert!(core::mem::size_of::<T>() <= BLOCK_SZ);
+    let mut block = [0u8; BLOCK_SZ];
+    unsafe {
+        core::ptr::write_unaligned(block.as_mut_ptr() as *mut T, *value);
+    }
+    block
+}
+
+/// Deserialize a journal block
+fn from_block<T: Copy>(block: &[u8]) -> T {
+    assert!(core::mem::size_of::<T>() <= block.len());
+    unsafe { core::ptr::read_unaligned(block.as_ptr() as *const T) }
+}
+
+/// FNV-1a hash of the home blocks and the contents of a transaction
+fn checksum(block_ids: &[u32], blocks: &[u8]) -> u32 {
+    let ids = block_ids.iter().flat_map(|block_id| block_id.to_le_bytes());
+    ids.chain(blocks.iter().copied())
+        .fold(0x811c9dc5u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x01000193)
+        })
+}
+
+/// Write-ahead journal of the blocks modified by each transaction
+///
+/// The journal is a header block followed by the transactions committed
+/// since the last checkpoint. A transaction is a descriptor block listing
+/// the home blocks, a copy of each of them, and a commit block with a
+/// checksum of them. The copies are written home afterwards, as the block
+/// cache writes the blocks back, and the journal is emptied by a
+/// checkpoint once they are all home.
+pub struct Journal {
+    block_device: Arc<dyn BlockDevice>,
+    /// The first block of the journal, which is the header
+    start_block: u32,
+    /// Number of blocks of the journal
+    blocks: u32,
+    /// Sequence of the next transaction
+    sequence: u32,
+    /// Block of the next transaction, relative to the start
+    head: u32,
+    /// Home blocks of the transactions since the last checkpoint
+    logged: BTreeSet<u32>,
+}
+
+impl Journal {
+    fn new(block_device: Arc<dyn BlockDevice>, start_block: u32, blocks: u32, sequence: u32) -> Self {
+        Self {
+            block_device,
+            start_block,
+            blocks,
+            sequence,
+            head: 1,
+            logged: BTreeSet::new(),
+        }
+    }
+    /// Write an empty journal of `blocks` from `start_block`
+    pub fn format(
+        block_device: Arc<dyn BlockDevice>,
+        start_block: u32,
+        blocks: u32,
+    ) -> FsResult<Self> {
+        assert!(blocks >= MIN_JOURNAL_BLOCKS);
+        let journal = Self::new(block_device, start_block, blocks, 1);
+        journal.write_header()?;
+        Ok(journal)
+    }
+    /// Open the journal of `blocks` from `start_block`, and write the
+    /// complete transactions in it home
+    ///
+    /// An incomplete transaction, and everything after it, is dropped.
+    pub fn replay(
+        block_device: Arc<dyn BlockDevice>,
+        start_block: u32,
+        blocks: u32,
+    ) -> FsResult<Self> {
+        if blocks < MIN_JOURNAL_BLOCKS {
+            return Err(FsError::Corrupted);
+        }
+        let mut block = [0u8; BLOCK_SZ];
+        block_cache_read_blocks(start_block as usize, &block_device, &mut block)?;
+        let header: JournalHeader = from_block(&block);
+        if !header.is_valid() {
+            return Err(FsError::Corrupted);
+        }
+        let mut journal = Self::new(block_device, start_block, blocks, header.sequence);
+        while journal.head + 2 <= blocks {
+            let descriptor_block = journal.start_block + journal.head;
+            block_cache_read_blocks(descriptor_block as usize, &journal.block_device, &mut block)?;
+            let descriptor: JournalDescriptor = from_block(&block);
+            let count = descriptor.count;
+            if !descriptor.is_valid(journal.sequence) || journal.head + count + 2 > blocks {
+                break;
+            }
+            let mut contents = vec![0u8; (count as usize + 1) * BLOCK_SZ];
+            block_cache_read_blocks(descriptor_block as usize + 1, &journal.block_device, &mut contents)?;
+            let (contents, commit_block) = contents.split_at(count as usize * BLOCK_SZ);
+            let commit: JournalCommit = from_block(commit_block);
+            let block_ids = descriptor.block_ids();
+            if !commit.is_valid(journal.sequence, checksum(block_ids, contents)) {
+                break;
+            }
+            if block_ids.iter().any(|&block_id| block_id >= start_block) {
+                return Err(FsError::Corrupted);
+            }
+            for (&block_id, content) in block_ids.iter().zip(contents.chunks_exact(BLOCK_SZ)) {
+                block_cache_write_blocks(block_id as usize, &journal.block_device, content)?;
+            }
+            journal.head += count + 2;
+            journal.sequence = journal.sequence.wrapping_add(1);
+        }
+        // the replayed blocks are home, and the journal is emptied
+        journal.block_device.flush()?;
+        journal.head = 1;
+        journal.write_header()?;
+        Ok(journal)
+    }
+    /// Write the header of an empty journal, whose transactions start from
+    /// the next sequence
+    fn write_header(&self) -> FsResult<()> {
+        let header = to_block(&JournalHeader::new(self.sequence));
+        block_cache_write_blocks(self.start_block as usize, &self.block_device, &header)?;
+        self.block_device.flush()
+    }
+    /// Whether a transaction since the last checkpoint modified `block_id`,
+    /// so that the journal may write it home again
+    pub fn is_logged(&self, block_id: u32) -> bool {
+        self.logged.contains(&block_id)
+    }
+    /// Commit the blocks modified since the last commit
+    ///
+    /// A transaction larger than [`MAX_TRANSACTION_BLOCKS`] can not be
+    /// committed atomically, so it fails with [`FsError::TooManyBlocks`]
+    /// and nothing is written, its blocks stay pending for the caller to
+    /// roll back.
+    pub fn commit(&mut self) -> FsResult<()> {
+        let pending = block_cache_pending_blocks(&self.block_device);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if pending.len() > MAX_TRANSACTION_BLOCKS {
+            return Err(FsError::TooManyBlocks);
+        }
+        let block_ids: Vec<u32> = pending.iter().map(|(block_id, _)| *block_id as u32).collect();
+        let mut blocks = Vec::with_capacity((pending.len() + 1) * BLOCK_SZ);
+        blocks.extend_from_slice(&to_block(&JournalDescriptor::new(self.sequence, &block_ids)));
+        for (_, content) in pending.iter() {
+            blocks.extend_from_slice(content);
+        }
+        let descriptor_block = (self.start_block + self.head) as usize;
+        block_cache_write_blocks(descriptor_block, &self.block_device, &blocks)?;
+        self.block_device.flush()?;
+        // the transaction is complete once the commit block is written
+        let commit = JournalCommit::new(self.sequence, checksum(&block_ids, &blocks[BLOCK_SZ..]));
+        let commit_block = descriptor_block + pending.len() + 1;
+        block_cache_write_blocks(commit_block, &self.block_device, &to_block(&commit))?;
+        self.block_device.flush()?;
+        self.head += pending.len() as u32 + 2;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.logged.extend(block_ids);
+        block_cache_clear_pending(&self.block_device);
+        // leave room for the largest transaction, while no block is pending
+        if self.head + MAX_TRANSACTION_BLOCKS as u32 + 2 > self.blocks {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+    /// Write the committed blocks home, and empty the journal
+    ///
+    /// There must be no pending blocks, whose committed contents would be
+    /// lost with the journal.
+    pub fn checkpoint(&mut self) -> FsResult<()> {
+        if self.head == 1 {
+            return Ok(());
+        }
+        block_cache_sync_device(&self.block_device)?;
+        self.head = 1;
+        self.logged.clear();
+        self.write_header()
+    }
+}
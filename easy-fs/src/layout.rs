@@ -2,17 +2,33 @@ use core::fmt::{Debug, Formatter, Result};
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    FsError,
+    FsResult,
     get_block_cache,
+    get_zeroed_block_cache,
+    block_cache_read_blocks,
+    block_cache_write_blocks,
 };
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-/// Magic number for sanity check
-const EFS_MAGIC: u32 = 0x3b800001;
-/// The max number of direct inodes
-const INODE_DIRECT_COUNT: usize = 28;
+/// Magic number for sanity check, which changes with the on-disk layout, so
+/// that an image of an older layout is rejected rather than misread
+///
+/// 0x3b800001 is the layout without link counts and the journal.
+const EFS_MAGIC: u32 = 0x3b800002;
+/// Magic number of a journal header block
+const JOURNAL_HEADER_MAGIC: u32 = 0x3b800101;
+/// Magic number of a journal descriptor block
+const JOURNAL_DESCRIPTOR_MAGIC: u32 = 0x3b800102;
+/// Magic number of a journal commit block
+const JOURNAL_COMMIT_MAGIC: u32 = 0x3b800103;
+/// The max number of block ids in a journal descriptor block
+pub const JOURNAL_DESCRIPTOR_IDS: usize = BLOCK_SZ / 4 - 3;
+/// The max number of direct inodes, which keeps a disk inode 128 bytes
+const INODE_DIRECT_COUNT: usize = 27;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 27;
 /// The max number of indirect1 inodes
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 inodes
@@ -22,8 +38,9 @@ const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 /// The upper bound of indirect1 inode index
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 /// The upper bound of indirect2 inode index
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// The max size of a file
+pub const MAX_FILE_SIZE: usize = INDIRECT2_BOUND * BLOCK_SZ;
 
 /// Super block of a filesystem
 #[repr(C)]
@@ -34,6 +51,9 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Blocks of the journal after the data area, the filesystem has no
+    /// journal if it is 0
+    pub journal_blocks: u32,
 }
 
 impl Debug for SuperBlock {
@@ -44,6 +64,7 @@ impl Debug for SuperBlock {
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("journal_blocks", &self.journal_blocks)
             .finish()
     }
 }
@@ -57,6 +78,7 @@ impl SuperBlock {
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        journal_blocks: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -65,6 +87,7 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            journal_blocks,
         }
     }
     /// Check if a super block is valid using efs magic
@@ -73,11 +96,95 @@ impl SuperBlock {
     }
 }
 
+/// The first block of the journal, whose sequence is that of the first
+/// transaction after it
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct JournalHeader {
+    magic: u32,
+    pub sequence: u32,
+}
+
+impl JournalHeader {
+    pub fn new(sequence: u32) -> Self {
+        Self {
+            magic: JOURNAL_HEADER_MAGIC,
+            sequence,
+        }
+    }
+    /// Check if a journal header is valid using its magic
+    pub fn is_valid(&self) -> bool {
+        self.magic == JOURNAL_HEADER_MAGIC
+    }
+}
+
+/// The first block of a transaction in the journal, which lists the home
+/// blocks of the following blocks
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct JournalDescriptor {
+    magic: u32,
+    pub sequence: u32,
+    pub count: u32,
+    pub block_ids: [u32; JOURNAL_DESCRIPTOR_IDS],
+}
+
+impl JournalDescriptor {
+    /// A descriptor of the blocks `block_ids`, at most
+    /// [`JOURNAL_DESCRIPTOR_IDS`] of them
+    pub fn new(sequence: u32, block_ids: &[u32]) -> Self {
+        let mut descriptor = Self {
+            magic: JOURNAL_DESCRIPTOR_MAGIC,
+            sequence,
+            count: block_ids.len() as u32,
+            block_ids: [0; JOURNAL_DESCRIPTOR_IDS],
+        };
+        descriptor.block_ids[..block_ids.len()].copy_from_slice(block_ids);
+        descriptor
+    }
+    /// Check if it is the descriptor of transaction `sequence`
+    pub fn is_valid(&self, sequence: u32) -> bool {
+        self.magic == JOURNAL_DESCRIPTOR_MAGIC
+            && self.sequence == sequence
+            && self.count as usize <= JOURNAL_DESCRIPTOR_IDS
+    }
+    /// Home blocks of the transaction
+    pub fn block_ids(&self) -> &[u32] {
+        &self.block_ids[..self.count as usize]
+    }
+}
+
+/// The last block of a transaction in the journal, the transaction is
+/// complete only if its checksum matches
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct JournalCommit {
+    magic: u32,
+    pub sequence: u32,
+    pub checksum: u32,
+}
+
+impl JournalCommit {
+    pub fn new(sequence: u32, checksum: u32) -> Self {
+        Self {
+            magic: JOURNAL_COMMIT_MAGIC,
+            sequence,
+            checksum,
+        }
+    }
+    /// Check if it commits transaction `sequence` of `checksum`
+    pub fn is_valid(&self, sequence: u32, checksum: u32) -> bool {
+        self.magic == JOURNAL_COMMIT_MAGIC
+            && self.sequence == sequence
+            && self.checksum == checksum
+    }
+}
+
 /// Type of a disk inode
-#[derive(PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DiskInodeType {
-    File,
-    Directory,
+    File = 0,
+    Directory = 1,
 }
 
 /// A indirect block
@@ -92,7 +199,11 @@ pub struct DiskInode {
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
-    type_: DiskInodeType,
+    /// Number of directory entries referring to the inode, including `.`
+    /// of a directory and `..` of its subdirectories
+    pub nlink: u32,
+    /// A [`DiskInodeType`] as it is on the disk, which may be corrupted
+    type_: u32,
 }
 
 impl DiskInode {
@@ -103,320 +214,447 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
-        self.type_ = type_;
+        // a directory is also referred to by its own `.`
+        self.nlink = match type_ {
+            DiskInodeType::File => 1,
+            DiskInodeType::Directory => 2,
+        };
+        self.type_ = type_ as u32;
+    }
+    /// Type of this inode, fail with [`FsError::Corrupted`] if it is none
+    /// of [`DiskInodeType`]
+    pub fn type_(&self) -> FsResult<DiskInodeType> {
+        match self.type_ {
+            type_ if type_ == DiskInodeType::File as u32 => Ok(DiskInodeType::File),
+            type_ if type_ == DiskInodeType::Directory as u32 => Ok(DiskInodeType::Directory),
+            _ => Err(FsError::Corrupted),
+        }
     }
     /// Whether this inode is a directory
-    pub fn is_dir(&self) -> bool {
-        self.type_ == DiskInodeType::Directory
+    pub fn is_dir(&self) -> FsResult<bool> {
+        Ok(self.type_()? == DiskInodeType::Directory)
     }
     /// Whether this inode is a file
     #[allow(unused)]
-    pub fn is_file(&self) -> bool {
-        self.type_ == DiskInodeType::File
+    pub fn is_file(&self) -> FsResult<bool> {
+        Ok(self.type_()? == DiskInodeType::File)
     }
     /// Get the number of data blocks corresponding to size
     pub fn data_blocks(&self) -> u32 {
         Self::_data_blocks(self.size)
     }
     fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+        ((size as u64 + BLOCK_SZ as u64 - 1) / BLOCK_SZ as u64) as u32
     }
-    /// Get the number of data blocks required for the given size of data
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
+    /// Get the data blocks and the indirect blocks of current disk inode
+    ///
+    /// Holes are skipped. Return `None` if a block is not accepted by
+    /// `is_data_block`, before it is read as an indirect block, or if the
+    /// blocks do not match the size, as those past the end are 0.
+    pub fn blocks(
+        &self,
+        is_data_block: impl Fn(u32) -> bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<Option<Vec<u32>>> {
+        let data_blocks = self.data_blocks() as usize;
+        if data_blocks > INDIRECT2_BOUND {
+            return Ok(None);
+        }
+        // collect the blocks of the first `count` entries, the rest are 0
+        let collect = |entries: &[u32], count: usize, v: &mut Vec<u32>| -> bool {
+            v.extend(entries[..count].iter().filter(|&&block_id| block_id != 0));
+            entries[count..].iter().all(|&block_id| block_id == 0)
+        };
+        let read_indirect = |block_id: u32, count: usize, v: &mut Vec<u32>| -> FsResult<Option<IndirectBlock>> {
+            if block_id == 0 {
+                return Ok(Some([0; INODE_INDIRECT1_COUNT]));
+            }
+            if count == 0 || !is_data_block(block_id) {
+                return Ok(None);
+            }
+            v.push(block_id);
+            let indirect_block = get_block_cache(block_id as usize, Arc::clone(block_device))?
+                .lock()
+                .read(0, |indirect_block: &IndirectBlock| *indirect_block);
+            Ok(collect(&indirect_block, count, v).then_some(indirect_block))
+        };
+        let mut v: Vec<u32> = Vec::new();
+        // direct
+        if !collect(&self.direct, data_blocks.min(DIRECT_BOUND), &mut v) {
+            return Ok(None);
+        }
         // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
+        let count = data_blocks.saturating_sub(DIRECT_BOUND).min(INODE_INDIRECT1_COUNT);
+        if read_indirect(self.indirect1, count, &mut v)?.is_none() {
+            return Ok(None);
         }
         // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+        let rest = data_blocks.saturating_sub(INDIRECT1_BOUND);
+        let count = (rest + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+        let indirect2 = match read_indirect(self.indirect2, count, &mut v)? {
+            Some(indirect2) => indirect2,
+            None => return Ok(None),
+        };
+        for (i, &indirect1) in indirect2[..count].iter().enumerate() {
+            let count = (rest - i * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+            if read_indirect(indirect1, count, &mut v)?.is_none() {
+                return Ok(None);
+            }
         }
-        total as u32
+        Ok(v.iter().all(|&block_id| is_data_block(block_id)).then_some(v))
     }
-    /// Get the number of data blocks that have to be allocated given the new size of data
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
-    }
-    /// Get id of block given inner id
-    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    /// Get id of block given inner id, which is 0 if the block is a hole
+    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> FsResult<u32> {
         let inner_id = inner_id as usize;
-        if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
+        if inner_id < DIRECT_BOUND {
+            Ok(self.direct[inner_id])
         } else if inner_id < INDIRECT1_BOUND {
-            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
+            Self::indirect_entry(self.indirect1, inner_id - DIRECT_BOUND, block_device)
         } else {
             let last = inner_id - INDIRECT1_BOUND;
-            let indirect1 = get_block_cache(
-                self.indirect2 as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |indirect2: &IndirectBlock| {
-                indirect2[last / INODE_INDIRECT1_COUNT]
-            });
-            get_block_cache(
-                indirect1 as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |indirect1: &IndirectBlock| {
-                indirect1[last % INODE_INDIRECT1_COUNT]
-            })
+            let indirect1 = Self::indirect_entry(
+                self.indirect2,
+                last / INODE_INDIRECT1_COUNT,
+                block_device,
+            )?;
+            Self::indirect_entry(indirect1, last % INODE_INDIRECT1_COUNT, block_device)
         }
     }
-    /// Inncrease the size of current disk inode
-    pub fn increase_size(
+    /// Get entry `i` of an indirect block, which is 0 if the indirect block
+    /// is a hole
+    fn indirect_entry(
+        indirect_block_id: u32,
+        i: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<u32> {
+        if indirect_block_id == 0 {
+            return Ok(0);
+        }
+        Ok(get_block_cache(indirect_block_id as usize, Arc::clone(block_device))?
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| indirect_block[i]))
+    }
+    /// Get id of block given inner id, allocating it with `alloc` if it is
+    /// a hole, as well as the indirect blocks leading to it
+    ///
+    /// New blocks are zeroed, except the data block if it is to be
+    /// overwritten `whole`.
+    fn map_block_id(
         &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+        inner_id: u32,
+        whole: bool,
+        alloc: &mut impl FnMut() -> FsResult<u32>,
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32{
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
-        } else {
-            return;
-        }
-        // fill indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                current_blocks += 1;
-            }
-        });
-        // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
+    ) -> FsResult<u32> {
+        let inner_id = inner_id as usize;
+        assert!(inner_id < INDIRECT2_BOUND);
+        if inner_id < DIRECT_BOUND {
+            if self.direct[inner_id] == 0 {
+                self.direct[inner_id] = alloc()?;
+                Self::zero_new_block(self.direct[inner_id], !whole, block_device)?;
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        } else {
-            return;
-        }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-        // alloc low-level indirect1
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                if b0 == 0 {
-                    indirect2[a0] = new_blocks.next().unwrap();
-                }
-                // fill current
-                get_block_cache(
-                    indirect2[a0] as usize,
-                    Arc::clone(block_device)
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    indirect1[b0] = new_blocks.next().unwrap();
-                });
-                // move to next
-                b0 += 1;
-                if b0 == INODE_INDIRECT1_COUNT {
-                    b0 = 0;
-                    a0 += 1;
-                }
+            return Ok(self.direct[inner_id]);
+        }
+        if inner_id < INDIRECT1_BOUND {
+            if self.indirect1 == 0 {
+                self.indirect1 = alloc()?;
+                Self::zero_new_block(self.indirect1, true, block_device)?;
             }
+            return Self::map_indirect_entry(self.indirect1, inner_id - DIRECT_BOUND, !whole, alloc, block_device);
+        }
+        if self.indirect2 == 0 {
+            self.indirect2 = alloc()?;
+            Self::zero_new_block(self.indirect2, true, block_device)?;
+        }
+        let last = inner_id - INDIRECT1_BOUND;
+        let indirect1 = Self::map_indirect_entry(
+            self.indirect2,
+            last / INODE_INDIRECT1_COUNT,
+            true,
+            alloc,
+            block_device,
+        )?;
+        Self::map_indirect_entry(indirect1, last % INODE_INDIRECT1_COUNT, !whole, alloc, block_device)
+    }
+    /// Get entry `i` of an indirect block, allocating a block for it with
+    /// `alloc` if it is 0, which is zeroed if `zeroed`
+    fn map_indirect_entry(
+        indirect_block_id: u32,
+        i: usize,
+        zeroed: bool,
+        alloc: &mut impl FnMut() -> FsResult<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<u32> {
+        let block_cache = get_block_cache(indirect_block_id as usize, Arc::clone(block_device))?;
+        let block_id = block_cache.lock().read(0, |indirect_block: &IndirectBlock| indirect_block[i]);
+        if block_id != 0 {
+            return Ok(block_id);
+        }
+        // the allocator goes through the block cache as well
+        let block_id = alloc()?;
+        block_cache.lock().modify(0, |indirect_block: &mut IndirectBlock| {
+            indirect_block[i] = block_id;
         });
+        drop(block_cache);
+        Self::zero_new_block(block_id, zeroed, block_device)?;
+        Ok(block_id)
+    }
+    /// Fill a block just mapped with zeroes if `zeroed`, it is already
+    /// mapped so that it is freed with the rest if this fails
+    fn zero_new_block(block_id: u32, zeroed: bool, block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+        if zeroed {
+            get_zeroed_block_cache(block_id as usize, Arc::clone(block_device))?;
+        }
+        Ok(())
     }
-    /// Clear size to zero and return blocks that should be deallocated
-    /// and clear the block contents to zero later
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    /// Shrink current disk inode to `new_size`, and return the data blocks
+    /// past it and the indirect blocks left empty, which should be
+    /// deallocated
+    ///
+    /// The rest of the new last block is zeroed, as the file reads it as
+    /// zeroes if it grows again.
+    pub fn truncate(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<Vec<u32>> {
+        assert!(new_size <= self.size);
+        let data_blocks = self.data_blocks() as usize;
+        if data_blocks > INDIRECT2_BOUND {
+            return Err(FsError::Corrupted);
+        }
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        let tail = new_size as usize % BLOCK_SZ;
+        if new_size < self.size && tail != 0 {
+            let block_id = self.get_block_id(new_blocks as u32 - 1, block_device)?;
+            if block_id != 0 {
+                get_block_cache(block_id as usize, Arc::clone(block_device))?
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| data_block[tail..].fill(0));
+            }
+        }
+        self.size = new_size;
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
         // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
+        let direct = &mut self.direct[new_blocks.min(DIRECT_BOUND)..data_blocks.min(DIRECT_BOUND)];
+        for block_id in direct.iter_mut().filter(|block_id| **block_id != 0) {
+            v.push(*block_id);
+            *block_id = 0;
         }
-        // indirect1 block
-        if data_blocks > INODE_DIRECT_COUNT {
+        // indirect1
+        let from = new_blocks.saturating_sub(DIRECT_BOUND).min(INODE_INDIRECT1_COUNT);
+        let to = data_blocks.saturating_sub(DIRECT_BOUND).min(INODE_INDIRECT1_COUNT);
+        if Self::truncate_indirect(self.indirect1, from, to, &mut v, block_device)? {
             v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
-            return v;
+            self.indirect1 = 0;
         }
-        // indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                v.push(indirect1[current_blocks]);
-                //indirect1[current_blocks] = 0;
-                current_blocks += 1;
+        // indirect2 from (a0, from) to (a1, to)
+        let from = new_blocks.saturating_sub(INDIRECT1_BOUND);
+        let to = data_blocks.saturating_sub(INDIRECT1_BOUND);
+        if self.indirect2 == 0 || from >= to {
+            return Ok(v);
+        }
+        let block_cache = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))?;
+        let mut indirect2 = block_cache.lock().read(0, |indirect2: &IndirectBlock| *indirect2);
+        let a0 = from / INODE_INDIRECT1_COUNT;
+        let a1 = (to + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+        for (a, indirect1) in indirect2.iter_mut().enumerate().take(a1).skip(a0) {
+            let base = a * INODE_INDIRECT1_COUNT;
+            let (from, to) = (from.saturating_sub(base), (to - base).min(INODE_INDIRECT1_COUNT));
+            if Self::truncate_indirect(*indirect1, from, to, &mut v, block_device)? {
+                v.push(*indirect1);
+                *indirect1 = 0;
             }
-        });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
+        }
+        if indirect2.iter().all(|&indirect1| indirect1 == 0) {
             v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
+            self.indirect2 = 0;
         } else {
-            return v;
+            block_cache.lock().modify(0, |old: &mut IndirectBlock| *old = indirect2);
         }
-        // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            // full indirect1 blocks
-            for i in 0..a1 {
-                v.push(indirect2[i]);
-                get_block_cache(
-                    indirect2[i] as usize,
-                    Arc::clone(block_device),
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..INODE_INDIRECT1_COUNT {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
-                });
-                //indirect2[i] = 0;
-            }
-            // last indirect1 block
-            if b1 > 0 {
-                v.push(indirect2[a1]);
-                get_block_cache(
-                    indirect2[a1] as usize,
-                    Arc::clone(block_device),
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..b1 {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
-                });
-                //indirect2[a1] = 0;
-            }
+        Ok(v)
+    }
+    /// Move entries `from..to` of an indirect block into `v`, and return
+    /// whether the indirect block is left empty
+    fn truncate_indirect(
+        indirect_block_id: u32,
+        from: usize,
+        to: usize,
+        v: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<bool> {
+        if indirect_block_id == 0 || from >= to {
+            return Ok(false);
+        }
+        let block_cache = get_block_cache(indirect_block_id as usize, Arc::clone(block_device))?;
+        let mut block_cache = block_cache.lock();
+        // entries past `to` are 0 already
+        let empty = block_cache.read(0, |indirect_block: &IndirectBlock| {
+            v.extend(indirect_block[from..to].iter().filter(|&&block_id| block_id != 0));
+            indirect_block[..from].iter().all(|&block_id| block_id == 0)
         });
-        self.indirect2 = 0;
-        v
+        if !empty {
+            block_cache.modify(0, |indirect_block: &mut IndirectBlock| {
+                indirect_block[from..to].fill(0);
+            });
+        }
+        Ok(empty)
     }
-    /// Read data from current disk inode
+    /// Read data from current disk inode, holes read as zeroes
+    ///
+    /// Whole blocks which are contiguous on the device are read at once.
     pub fn read_at(
         &self,
         offset: usize,
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
-    ) -> usize {
+    ) -> FsResult<usize> {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         if start >= end {
-            return 0;
+            return Ok(0);
         }
         let mut start_block = start / BLOCK_SZ;
         let mut read_size = 0usize;
+        let mut run = BlockRun::new();
         loop {
             // calculate end of current block
             let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
             end_current_block = end_current_block.min(end);
             // read and update read size
             let block_read_size = end_current_block - start;
+            let block_id = self.get_block_id(start_block as u32, block_device)? as usize;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            if block_id == 0 {
+                dst.fill(0);
+                // a run is contiguous in the buffer
+                run.read(buf, block_device)?;
+                run = BlockRun::new();
+            } else if block_read_size == BLOCK_SZ {
+                if !run.extend(block_id, read_size) {
+                    run.read(buf, block_device)?;
+                    run = BlockRun::new();
+                    run.extend(block_id, read_size);
+                }
+            } else {
+                get_block_cache(block_id, Arc::clone(block_device))?
+                .lock()
+                .read(0, |data_block: &DataBlock| {
+                    let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                    dst.copy_from_slice(src);
+                });
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end { break; }
             start_block += 1;
             start = end_current_block;
         }
-        read_size
+        run.read(buf, block_device)?;
+        Ok(read_size)
     }
     /// Write data into current disk inode
     /// size must be adjusted properly beforehand
+    ///
+    /// Holes written to are allocated with `alloc`. Whole blocks which are
+    /// contiguous on the device are written at once.
     pub fn write_at(
         &mut self,
         offset: usize,
         buf: &[u8],
+        alloc: &mut impl FnMut() -> FsResult<u32>,
         block_device: &Arc<dyn BlockDevice>,
-    ) -> usize {
+    ) -> FsResult<usize> {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         assert!(start <= end);
+        if start == end {
+            return Ok(0);
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
+        let mut run = BlockRun::new();
         loop {
             // calculate end of current block
             let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                let src = &buf[write_size..write_size + block_write_size];
-                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
-                dst.copy_from_slice(src);
-            });
+            let whole = block_write_size == BLOCK_SZ;
+            let block_id = self.map_block_id(start_block as u32, whole, alloc, block_device)? as usize;
+            if whole {
+                if !run.extend(block_id, write_size) {
+                    run.write(buf, block_device)?;
+                    run = BlockRun::new();
+                    run.extend(block_id, write_size);
+                }
+            } else {
+                get_block_cache(block_id, Arc::clone(block_device))?
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    let src = &buf[write_size..write_size + block_write_size];
+                    let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
+                    dst.copy_from_slice(src);
+                });
+            }
             write_size += block_write_size;
             // move to next block
             if end_current_block == end { break; }
             start_block += 1;
             start = end_current_block;
         }
-        write_size
+        run.write(buf, block_device)?;
+        Ok(write_size)
+    }
+}
+
+/// Whole blocks of a file which are contiguous on the device, and in the
+/// buffer of a read or a write
+struct BlockRun {
+    /// Device block id of the first block
+    block_id: usize,
+    /// Offset of the first block in the buffer
+    offset: usize,
+    /// Number of blocks
+    count: usize,
+}
+
+impl BlockRun {
+    fn new() -> Self {
+        Self {
+            block_id: 0,
+            offset: 0,
+            count: 0,
+        }
+    }
+    /// Append the block `block_id` at `offset` of the buffer, which follows
+    /// the run in the buffer, return false if it does not follow the run on
+    /// the device
+    fn extend(&mut self, block_id: usize, offset: usize) -> bool {
+        if self.count == 0 {
+            self.block_id = block_id;
+            self.offset = offset;
+        } else if block_id != self.block_id + self.count {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+    /// The part of `buf` covered by the run
+    fn range(&self) -> core::ops::Range<usize> {
+        self.offset..self.offset + self.count * BLOCK_SZ
+    }
+    fn read(&self, buf: &mut [u8], block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        block_cache_read_blocks(self.block_id, block_device, &mut buf[self.range()])
+    }
+    fn write(&self, buf: &[u8], block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        block_cache_write_blocks(self.block_id, block_device, &buf[self.range()])
     }
 }
 
@@ -465,13 +703,20 @@ impl DirEntry {
             )
         }
     }
-    /// Get name of the entry
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    /// Get name of the entry, return `None` unless it is a valid name,
+    /// which is not empty and has no `/`
+    pub fn checked_name(&self) -> Option<&str> {
+        let len = self.name.iter().position(|&ch| ch == 0)?;
+        core::str::from_utf8(&self.name[..len])
+            .ok()
+            .filter(|name| !name.is_empty() && !name.contains('/'))
     }
     /// Get inode number of the entry
     pub fn inode_number(&self) -> u32 {
         self.inode_number
     }
+    /// Whether the entry is unused, or has been removed
+    pub fn is_empty(&self) -> bool {
+        self.name[0] == 0
+    }
 }
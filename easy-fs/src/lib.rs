@@ -8,12 +8,30 @@ mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod error;
+mod journal;
+pub mod fsck;
 
 /// Use a block size of 512 bytes
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
+pub use block_cache::set_block_cache_capacity;
 pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use error::{FsError, FsResult};
+pub use layout::DiskInodeType;
+pub use vfs::{Inode, Stat};
 use layout::*;
-use bitmap::Bitmap;
-use block_cache::{get_block_cache, block_cache_sync_all};
+use bitmap::{Bitmap, BLOCK_BITS};
+use block_cache::{
+    get_block_cache,
+    get_zeroed_block_cache,
+    block_cache_read_blocks,
+    block_cache_write_blocks,
+    block_cache_discard,
+    block_cache_pending_blocks,
+    block_cache_clear_pending,
+    block_cache_discard_pending,
+    block_cache_sync_device,
+    block_cache_drop_device,
+};
+use journal::{Journal, MIN_JOURNAL_BLOCKS};
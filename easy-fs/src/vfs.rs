@@ -4,15 +4,34 @@ use super::{
     DiskInodeType,
     DirEntry,
     EasyFileSystem,
+    FsError,
+    FsResult,
     DIRENT_SZ,
+    NAME_LENGTH_LIMIT,
+    MAX_FILE_SIZE,
+    BLOCK_SZ,
     get_block_cache,
-    block_cache_sync_all,
 };
 use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::vec::Vec;
 use spin::{Mutex, MutexGuard};
 
+/// The max number of blocks a write adds to a file in one transaction
+const WRITE_CHUNK_BLOCKS: usize = 64;
+
+/// Status of an inode
+#[derive(Debug)]
+pub struct Stat {
+    /// Inode number
+    pub ino: u32,
+    pub type_: DiskInodeType,
+    /// Size in bytes
+    pub size: u32,
+    /// Number of hard links
+    pub nlink: u32,
+}
+
 /// Virtual filesystem layer over easy-fs
 pub struct Inode {
     block_id: usize,
@@ -37,175 +56,448 @@ impl Inode {
         }
     }
     /// Call a function over a disk inode to read it
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> FsResult<V>) -> FsResult<V> {
         get_block_cache(
             self.block_id,
             Arc::clone(&self.block_device)
-        ).lock().read(self.block_offset, f)
+        )?.lock().read(self.block_offset, f)
     }
     /// Call a function over a disk inode to modify it
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> FsResult<V>) -> FsResult<V> {
         get_block_cache(
             self.block_id,
             Arc::clone(&self.block_device)
-        ).lock().modify(self.block_offset, f)
+        )?.lock().modify(self.block_offset, f)
+    }
+    /// Id of the inode
+    fn inode_id(&self, fs: &EasyFileSystem) -> u32 {
+        fs.get_inode_id(self.block_id as u32, self.block_offset)
+    }
+    /// The inode `inode_id` of the same filesystem
+    fn get_inode(&self, inode_id: u32, fs: &EasyFileSystem) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+        ))
+    }
+    /// Whether the inode is a directory
+    pub fn is_dir(&self) -> FsResult<bool> {
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+    /// Read the `i`-th directory entry of a directory
+    fn read_dirent(&self, i: usize, disk_inode: &DiskInode) -> FsResult<DirEntry> {
+        let mut dirent = DirEntry::empty();
+        if disk_inode.read_at(
+            DIRENT_SZ * i,
+            dirent.as_bytes_mut(),
+            &self.block_device,
+        )? != DIRENT_SZ {
+            return Err(FsError::Corrupted);
+        }
+        Ok(dirent)
     }
-    /// Find inode under a disk inode by name
-    fn find_inode_id(
+    /// Find the directory entry of name under a disk inode,
+    /// return its index and inode id
+    fn find_dirent(
         &self,
         name: &str,
         disk_inode: &DiskInode,
-    ) -> Option<u32> {
-        // assert it is a directory
-        assert!(disk_inode.is_dir());
+    ) -> FsResult<Option<(usize, u32)>> {
+        if !disk_inode.is_dir()? {
+            return Err(FsError::NotDir);
+        }
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
         for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(
-                    DIRENT_SZ * i,
-                    dirent.as_bytes_mut(),
-                    &self.block_device,
-                ),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
-            }
-        }
-        None
-    }
-    /// Find inode under current inode by name
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
+            let dirent = self.read_dirent(i, disk_inode)?;
+            if dirent.is_empty() {
+                continue;
+            }
+            if dirent.checked_name().ok_or(FsError::Corrupted)? == name {
+                return Ok(Some((i, dirent.inode_number())));
+            }
+        }
+        Ok(None)
+    }
+    /// Find inode under current directory by name
+    fn find_child(&self, name: &str) -> FsResult<Option<(usize, u32)>> {
+        self.read_disk_inode(|disk_inode| self.find_dirent(name, disk_inode))
+    }
+    /// Resolve `path`, absolute or relative to current inode, with the
+    /// filesystem locked
+    ///
+    /// `.` and `..` are ordinary directory entries, and `..` of the root is
+    /// the root itself. An empty path is current inode.
+    fn lookup(&self, path: &str, fs: &EasyFileSystem) -> FsResult<Arc<Inode>> {
+        let mut inode = if path.starts_with('/') {
+            self.get_inode(0, fs)
+        } else {
+            self.get_inode(self.inode_id(fs), fs)
+        };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let (_, inode_id) = inode.find_child(name)?.ok_or(FsError::NotFound)?;
+            inode = inode.get_inode(inode_id, fs);
+        }
+        Ok(inode)
+    }
+    /// Resolve the parent directory of `path`, return it with the last
+    /// component of `path`
+    fn lookup_parent<'a>(
+        &self,
+        path: &'a str,
+        fs: &EasyFileSystem,
+    ) -> FsResult<(Arc<Inode>, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (parent_path, name) = match path.rfind('/') {
+            Some(pos) => (&path[..pos + 1], &path[pos + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() {
+            return Err(FsError::Invalid);
+        }
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(FsError::NameTooLong);
+        }
+        let parent = self.lookup(parent_path, fs)?;
+        if parent.is_dir()? {
+            Ok((parent, name))
+        } else {
+            Err(FsError::NotDir)
+        }
+    }
+    /// Run an operation with the filesystem locked, and commit the blocks
+    /// it modifies as a transaction
+    ///
+    /// If the operation or the commit fails, the blocks it has modified are
+    /// rolled back instead, as a failed operation may stop halfway.
+    fn transaction<V>(
+        &self,
+        op: impl FnOnce(&mut MutexGuard<EasyFileSystem>) -> FsResult<V>,
+    ) -> FsResult<V> {
+        let mut fs = self.fs.lock();
+        let result = op(&mut fs).and_then(|value| fs.commit().map(|()| value));
+        if result.is_err() {
+            fs.rollback();
+        }
+        result
+    }
+    /// Find inode by path, absolute or relative to current inode
+    pub fn find(&self, path: &str) -> FsResult<Arc<Inode>> {
         let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
-            .map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
-        })
+        self.lookup(path, &fs)
+    }
+    /// Write to a disk inode, which grows if the write ends past its end
+    ///
+    /// The holes written to are allocated, and if it fails, the transaction
+    /// rolls the allocation back.
+    fn write_disk_inode(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> FsResult<usize> {
+        disk_inode.size = disk_inode.size.max((offset + buf.len()) as u32);
+        disk_inode.write_at(offset, buf, &mut || fs.alloc_data(), &self.block_device)
     }
-    /// Increase the size of a disk inode
-    fn increase_size(
+    /// Shrink a disk inode to `new_size`, and free the blocks past it
+    fn shrink(
         &self,
         new_size: u32,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size {
-            return;
-        }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+    ) -> FsResult<()> {
+        for block_id in disk_inode.truncate(new_size, &self.block_device)? {
+            fs.dealloc_data(block_id)?;
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        Ok(())
     }
-    /// Create inode under current inode by name
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        if self.modify_disk_inode(|root_inode| {
-            // assert it is a directory
-            assert!(root_inode.is_dir());
-            // has the file been created?
-            self.find_inode_id(name, root_inode)
-        }).is_some() {
-            return None;
+    /// Add a directory entry to current directory, reusing a removed one
+    pub(crate) fn add_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> FsResult<()> {
+        self.modify_disk_inode(|dir_inode| {
+            let file_count = (dir_inode.size as usize) / DIRENT_SZ;
+            let mut i = file_count;
+            for j in 0..file_count {
+                if self.read_dirent(j, dir_inode)?.is_empty() {
+                    i = j;
+                    break;
+                }
+            }
+            // write dirent, which is appended if i == file_count
+            let dirent = DirEntry::new(name, inode_id);
+            self.write_disk_inode(i * DIRENT_SZ, dirent.as_bytes(), dir_inode, fs)?;
+            Ok(())
+        })
+    }
+    /// Mark the `i`-th directory entry of current directory as removed
+    fn remove_dirent(&self, i: usize) -> FsResult<()> {
+        self.modify_disk_inode(|dir_inode| {
+            dir_inode.write_at(
+                i * DIRENT_SZ,
+                DirEntry::empty().as_bytes(),
+                // a directory has no holes
+                &mut || Err(FsError::Corrupted),
+                &self.block_device,
+            )?;
+            Ok(())
+        })
+    }
+    /// Create an inode of `type_` by path, with the filesystem locked
+    fn create_inode(
+        &self,
+        path: &str,
+        type_: DiskInodeType,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> FsResult<Arc<Inode>> {
+        let (parent, name) = self.lookup_parent(path, fs)?;
+        // has the file been created?
+        if name == "." || name == ".." || parent.find_child(name)?.is_some() {
+            return Err(FsError::Exists);
         }
         // create a new file
         // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+        let new_inode_id = fs.alloc_inode()?;
         // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) 
-            = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(
-            new_inode_block_id as usize,
-            Arc::clone(&self.block_device)
-        ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-            new_inode.initialize(DiskInodeType::File);
-        });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
-        // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
-        // release efs lock automatically by compiler
+        let new_inode = self.get_inode(new_inode_id, fs);
+        new_inode.modify_disk_inode(|disk_inode| {
+            disk_inode.initialize(type_);
+            Ok(())
+        })?;
+        let is_dir = type_ == DiskInodeType::Directory;
+        let linked = (|| {
+            if is_dir {
+                new_inode.add_dirent(".", new_inode_id, fs)?;
+                new_inode.add_dirent("..", parent.inode_id(fs), fs)?;
+            }
+            parent.add_dirent(name, new_inode_id, fs)
+        })();
+        if let Err(err) = linked {
+            new_inode.free(new_inode_id, fs)?;
+            return Err(err);
+        }
+        if is_dir {
+            parent.modify_disk_inode(|parent_inode| {
+                parent_inode.nlink += 1;
+                Ok(())
+            })?;
+        }
+        Ok(new_inode)
+    }
+    /// Create a file by path, absolute or relative to current inode
+    pub fn create(&self, path: &str) -> FsResult<Arc<Inode>> {
+        self.transaction(|fs| self.create_inode(path, DiskInodeType::File, fs))
+    }
+    /// Create a directory by path, absolute or relative to current inode
+    pub fn mkdir(&self, path: &str) -> FsResult<Arc<Inode>> {
+        self.transaction(|fs| self.create_inode(path, DiskInodeType::Directory, fs))
+    }
+    /// Remove an empty directory by path
+    ///
+    /// The root, `.` and `..` can not be removed.
+    pub fn rmdir(&self, path: &str) -> FsResult<()> {
+        self.transaction(|fs| self.remove_dir(path, fs))
+    }
+    /// Remove an empty directory by path, with the filesystem locked
+    fn remove_dir(&self, path: &str, fs: &mut MutexGuard<EasyFileSystem>) -> FsResult<()> {
+        let (parent, name) = self.lookup_parent(path, fs)?;
+        if name == "." || name == ".." {
+            return Err(FsError::Invalid);
+        }
+        let (i, inode_id) = parent.find_child(name)?.ok_or(FsError::NotFound)?;
+        let dir = self.get_inode(inode_id, fs);
+        dir.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir()? {
+                return Err(FsError::NotDir);
+            }
+            for i in 0..disk_inode.size as usize / DIRENT_SZ {
+                let dirent = dir.read_dirent(i, disk_inode)?;
+                if dirent.is_empty() {
+                    continue;
+                }
+                let name = dirent.checked_name().ok_or(FsError::Corrupted)?;
+                if name != "." && name != ".." {
+                    return Err(FsError::NotEmpty);
+                }
+            }
+            Ok(())
+        })?;
+        parent.remove_dirent(i)?;
+        parent.modify_disk_inode(|parent_inode| {
+            parent_inode.nlink -= 1;
+            Ok(())
+        })?;
+        dir.free(inode_id, fs)
+    }
+    /// Add a hard link `new_path` to the file `old_path`
+    ///
+    /// Directories can not be linked.
+    pub fn link(&self, old_path: &str, new_path: &str) -> FsResult<()> {
+        self.transaction(|fs| {
+            let file = self.lookup(old_path, fs)?;
+            if file.is_dir()? {
+                return Err(FsError::IsDir);
+            }
+            let (parent, name) = self.lookup_parent(new_path, fs)?;
+            if name == "." || name == ".." || parent.find_child(name)?.is_some() {
+                return Err(FsError::Exists);
+            }
+            parent.add_dirent(name, file.inode_id(fs), fs)?;
+            file.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink += 1;
+                Ok(())
+            })
+        })
     }
-    /// List inodes under current inode
-    pub fn ls(&self) -> Vec<String> {
+    /// Remove a link to a file by path
+    ///
+    /// Directories are removed by [`Inode::rmdir`] instead. When the last
+    /// link is removed, the inode and its data blocks are freed at once, and
+    /// must not be used through any other `Inode` afterwards.
+    pub fn unlink(&self, path: &str) -> FsResult<()> {
+        self.transaction(|fs| {
+            let (parent, name) = self.lookup_parent(path, fs)?;
+            let (i, inode_id) = parent.find_child(name)?.ok_or(FsError::NotFound)?;
+            let file = self.get_inode(inode_id, fs);
+            if file.is_dir()? {
+                return Err(FsError::IsDir);
+            }
+            let nlink = file.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink = disk_inode.nlink.checked_sub(1).ok_or(FsError::Corrupted)?;
+                Ok(disk_inode.nlink)
+            })?;
+            parent.remove_dirent(i)?;
+            if nlink == 0 {
+                file.free(inode_id, fs)?;
+            }
+            Ok(())
+        })
+    }
+    /// Free the data blocks of current inode, whose id is `inode_id`,
+    /// and the inode itself
+    fn free(&self, inode_id: u32, fs: &mut MutexGuard<EasyFileSystem>) -> FsResult<()> {
+        self.clear_data(fs)?;
+        fs.dealloc_inode(inode_id)
+    }
+    /// Free the data blocks of current inode
+    fn clear_data(&self, fs: &mut MutexGuard<EasyFileSystem>) -> FsResult<()> {
+        self.modify_disk_inode(|disk_inode| self.shrink(0, disk_inode, fs))
+    }
+    /// Get the status of current inode
+    pub fn stat(&self) -> FsResult<Stat> {
+        let fs = self.fs.lock();
+        let ino = self.inode_id(&fs);
+        self.read_disk_inode(|disk_inode| Ok(Stat {
+            ino,
+            type_: disk_inode.type_()?,
+            size: disk_inode.size,
+            nlink: disk_inode.nlink,
+        }))
+    }
+    /// List inodes under current directory, except `.` and `..`
+    pub fn ls(&self) -> FsResult<Vec<String>> {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir()? {
+                return Err(FsError::NotDir);
+            }
             let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             let mut v: Vec<String> = Vec::new();
             for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(
-                        i * DIRENT_SZ,
-                        dirent.as_bytes_mut(),
-                        &self.block_device,
-                    ),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
-            }
-            v
+                let dirent = self.read_dirent(i, disk_inode)?;
+                if dirent.is_empty() {
+                    continue;
+                }
+                let name = dirent.checked_name().ok_or(FsError::Corrupted)?;
+                if name != "." && name != ".." {
+                    v.push(String::from(name));
+                }
+            }
+            Ok(v)
         })
     }
-    /// Read data from current inode
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    /// Read data from current file
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize> {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_dir()? {
+                return Err(FsError::IsDir);
+            }
             disk_inode.read_at(offset, buf, &self.block_device)
         })
     }
-    /// Write data to current inode
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
-        });
-        block_cache_sync_all();
-        size
-    }
-    /// Clear the data in current inode
-    pub fn clear(&self) {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
-            }
-        });
-        block_cache_sync_all();
+    /// Write data to current file
+    ///
+    /// A write past the end leaves a hole up to `offset`, which reads as
+    /// zeroes and has no blocks. The file grows by at most
+    /// [`WRITE_CHUNK_BLOCKS`] in a transaction, so a long write may be cut
+    /// short by a failure, and the bytes written before it are reported.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> FsResult<usize> {
+        let end = offset
+            .checked_add(buf.len())
+            .filter(|&size| size <= MAX_FILE_SIZE)
+            .ok_or(FsError::NoSpace)?;
+        const CHUNK_SZ: usize = WRITE_CHUNK_BLOCKS * BLOCK_SZ;
+        let mut start = offset;
+        loop {
+            let chunk_end = end.min((start / CHUNK_SZ + 1) * CHUNK_SZ);
+            let written = self.transaction(|fs| self.modify_disk_inode(|disk_inode| {
+                if disk_inode.is_dir()? {
+                    return Err(FsError::IsDir);
+                }
+                self.write_disk_inode(start, &buf[start - offset..chunk_end - offset], disk_inode, fs)
+            }));
+            match written {
+                Ok(written) => start += written,
+                Err(_) if start > offset => break,
+                Err(err) => return Err(err),
+            }
+            if start >= end {
+                break;
+            }
+        }
+        Ok(start - offset)
+    }
+    /// Truncate current file to `new_size`
+    ///
+    /// The blocks past the new end are freed, or the file is extended with
+    /// a hole.
+    pub fn truncate(&self, new_size: usize) -> FsResult<()> {
+        if new_size > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        self.transaction(|fs| self.modify_disk_inode(|disk_inode| {
+            if disk_inode.is_dir()? {
+                return Err(FsError::IsDir);
+            }
+            if (new_size as u32) < disk_inode.size {
+                self.shrink(new_size as u32, disk_inode, fs)
+            } else {
+                disk_inode.size = new_size as u32;
+                Ok(())
+            }
+        }))
+    }
+    /// Write the changes of the whole filesystem back to the disk
+    ///
+    /// Changes are cached in memory until then, or until their blocks are
+    /// evicted from the block cache.
+    pub fn sync(&self) -> FsResult<()> {
+        self.fs.lock().sync()
+    }
+    /// Clear the data in current file
+    pub fn clear(&self) -> FsResult<()> {
+        self.transaction(|fs| {
+            if self.is_dir()? {
+                return Err(FsError::IsDir);
+            }
+            self.clear_data(fs)
+        })
     }
 }
@@ -1,4 +1,9 @@
 pub const USER_STACK_SIZE: usize = 4096 * 2;
+/// Limit on the argv strings and pointers passed to a new program, which are
+/// pushed onto its user stack
+pub const ARG_MAX: usize = 4096;
+/// Max number of open file descriptors of a process
+pub const MAX_FDS: usize = 128;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 20;
 pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
 pub const MEMORY_END: usize = 0x88000000;
@@ -7,6 +12,16 @@ pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const MAX_SYSCALL_NUM: usize = 500;
 pub const BIG_STRIDE: usize = usize::MAX / 1048576;
 
+/// End of the lower half of SV39, user mappings must stay below it
+pub const USER_SPACE_END: usize = 1 << 38;
+
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
 pub const CLOCK_FREQ: usize = 12500000;
+/// Memory-mapped device registers of the QEMU virt machine, as (start, len)
+pub const MMIO: &[(usize, usize)] = &[
+    (0x1000_1000, 0x1000), // virtio-blk
+];
+/// Harts with an id from 0 to `MAX_HARTS - 1` are used, `entry.asm` sets
+/// aside a boot stack for each of them
+pub const MAX_HARTS: usize = 8;
@@ -3,6 +3,7 @@
 */
 
 use crate::sbi::console_putchar;
+use crate::sync::SpinLock;
 use core::fmt::{self, Write};
 
 struct Stdout;
@@ -16,8 +17,11 @@ impl Write for Stdout {
     }
 }
 
+/// Keep the output of different harts from interleaving
+static STDOUT: SpinLock<Stdout> = SpinLock::new(Stdout);
+
 pub fn print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+    STDOUT.exclusive_access().write_fmt(args).unwrap();
 }
 
 #[macro_export]
@@ -0,0 +1,13 @@
+//! Block devices, which easy-fs is mounted on
+
+mod virtio_blk;
+
+use alloc::sync::Arc;
+use easy_fs::BlockDevice;
+use lazy_static::*;
+use virtio_blk::VirtIOBlock;
+
+lazy_static! {
+    /// The disk with the root file system, `fs.img` attached by QEMU
+    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(VirtIOBlock::new());
+}
@@ -0,0 +1,425 @@
+//! Driver of the virtio block device on the MMIO bus of QEMU virt
+//!
+//! Requests go through a single virtqueue one at a time, and the driver
+//! polls for their completion. A request is a descriptor chain of the
+//! request header, the physically contiguous pieces of the data buffer, and
+//! the status byte, so contiguous blocks are transferred by one request.
+
+use crate::config::PAGE_SIZE;
+use crate::mm::{frame_alloc, FrameTracker, PhysAddr, VirtAddr, KERNEL_SPACE};
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use easy_fs::{BlockDevice, FsError, FsResult, BLOCK_SZ};
+
+/// Base of the MMIO registers of the first virtio device
+const VIRTIO0: usize = 0x1000_1000;
+
+// Offsets of the MMIO registers, those of the legacy interface are marked
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+/// Legacy
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+/// Legacy
+const QUEUE_ALIGN: usize = 0x03c;
+/// Legacy
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const STATUS: usize = 0x070;
+const QUEUE_DESC: usize = 0x080;
+const QUEUE_DRIVER: usize = 0x090;
+const QUEUE_DEVICE: usize = 0x0a0;
+/// `seg_max` in the configuration of a block device
+const CONFIG_SEG_MAX: usize = 0x100 + 12;
+
+const MAGIC: u32 = 0x7472_6976;
+const BLOCK_DEVICE_ID: u32 = 2;
+
+// Device status bits
+const ACKNOWLEDGE: u32 = 1;
+const DRIVER: u32 = 2;
+const DRIVER_OK: u32 = 4;
+const FEATURES_OK: u32 = 8;
+
+/// The device limits the number of data segments of a request
+const VIRTIO_BLK_F_SEG_MAX: u32 = 1 << 2;
+/// Bit 32 of the features, which a modern device requires
+const VIRTIO_F_VERSION_1: u32 = 1;
+
+// Request types and status
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// Descriptor flags
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Number of descriptors of the virtqueue
+const QUEUE_SIZE: usize = 16;
+/// Descriptors of a request besides its data, the header and the status
+const REQUEST_DESCS: usize = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    type_: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+fn read_reg(offset: usize) -> u32 {
+    unsafe { read_volatile((VIRTIO0 + offset) as *const u32) }
+}
+
+fn write_reg(offset: usize, value: u32) {
+    unsafe { write_volatile((VIRTIO0 + offset) as *mut u32, value) }
+}
+
+/// Write a 64-bit address into a pair of registers
+fn write_reg_addr(offset: usize, addr: usize) {
+    write_reg(offset, addr as u32);
+    write_reg(offset + 4, (addr >> 32) as u32);
+}
+
+/// The virtqueue and the memory of the request being served
+///
+/// Physical memory is identically mapped in kernel space, so the frames are
+/// accessed at their physical addresses. The first two frames hold the
+/// descriptor table with the available ring, and the used ring, as the
+/// legacy interface lays them out. The third holds the request header, the
+/// status byte, and a bounce buffer of a block.
+struct VirtIOBlk {
+    frames: Vec<FrameTracker>,
+    /// Index of the next available ring entry
+    avail_idx: u16,
+    /// Used ring index of the last completed request
+    last_used: u16,
+    /// The max number of data segments of a request
+    max_segments: usize,
+}
+
+impl VirtIOBlk {
+    fn new() -> Self {
+        assert!(
+            read_reg(MAGIC_VALUE) == MAGIC && read_reg(DEVICE_ID) == BLOCK_DEVICE_ID,
+            "no virtio block device"
+        );
+        let version = read_reg(VERSION);
+        write_reg(STATUS, 0);
+        let mut status = ACKNOWLEDGE | DRIVER;
+        write_reg(STATUS, status);
+        write_reg(DEVICE_FEATURES_SEL, 0);
+        let features = read_reg(DEVICE_FEATURES) & VIRTIO_BLK_F_SEG_MAX;
+        write_reg(DRIVER_FEATURES_SEL, 0);
+        write_reg(DRIVER_FEATURES, features);
+        if version >= 2 {
+            write_reg(DRIVER_FEATURES_SEL, 1);
+            write_reg(DRIVER_FEATURES, VIRTIO_F_VERSION_1);
+            status |= FEATURES_OK;
+            write_reg(STATUS, status);
+            assert!(
+                read_reg(STATUS) & FEATURES_OK != 0,
+                "virtio features rejected"
+            );
+        } else {
+            write_reg(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        }
+        // the queue frames must be contiguous
+        let frames: Vec<FrameTracker> = (0..3)
+            .map(|_| frame_alloc().expect("no frame for the virtqueue"))
+            .collect();
+        assert_eq!(frames[1].ppn.0, frames[0].ppn.0 + 1);
+        write_reg(QUEUE_SEL, 0);
+        assert!(
+            read_reg(QUEUE_NUM_MAX) as usize >= QUEUE_SIZE,
+            "virtqueue too small"
+        );
+        write_reg(QUEUE_NUM, QUEUE_SIZE as u32);
+        let queue = PhysAddr::from(frames[0].ppn).0;
+        if version >= 2 {
+            write_reg_addr(QUEUE_DESC, queue);
+            write_reg_addr(
+                QUEUE_DRIVER,
+                queue + QUEUE_SIZE * core::mem::size_of::<Descriptor>(),
+            );
+            write_reg_addr(QUEUE_DEVICE, queue + PAGE_SIZE);
+            write_reg(QUEUE_READY, 1);
+        } else {
+            write_reg(QUEUE_ALIGN, PAGE_SIZE as u32);
+            write_reg(QUEUE_PFN, frames[0].ppn.0 as u32);
+        }
+        write_reg(STATUS, status | DRIVER_OK);
+        let mut max_segments = QUEUE_SIZE - REQUEST_DESCS;
+        if features & VIRTIO_BLK_F_SEG_MAX != 0 {
+            max_segments = max_segments.min(read_reg(CONFIG_SEG_MAX) as usize);
+        }
+        Self {
+            frames,
+            avail_idx: 0,
+            last_used: 0,
+            max_segments,
+        }
+    }
+    fn queue(&self) -> usize {
+        PhysAddr::from(self.frames[0].ppn).0
+    }
+    fn descriptor(&self, i: usize) -> *mut Descriptor {
+        (self.queue() as *mut Descriptor).wrapping_add(i)
+    }
+    fn avail_ring(&self) -> *mut AvailRing {
+        self.descriptor(QUEUE_SIZE) as *mut AvailRing
+    }
+    fn used_ring(&self) -> *const UsedRing {
+        (self.queue() + PAGE_SIZE) as *const UsedRing
+    }
+    fn header(&self) -> usize {
+        PhysAddr::from(self.frames[2].ppn).0
+    }
+    fn status(&self) -> usize {
+        self.header() + core::mem::size_of::<RequestHeader>()
+    }
+    fn bounce_buffer(&self) -> usize {
+        self.header() + BLOCK_SZ
+    }
+    /// Transfer the blocks from `block_id` to or from the `segments` of
+    /// physical address and length, and wait until it is done
+    fn request(
+        &mut self,
+        type_: u32,
+        block_id: usize,
+        segments: &[(usize, usize)],
+    ) -> FsResult<()> {
+        assert!(segments.len() <= self.max_segments);
+        let header = RequestHeader {
+            type_,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let data_flags = match type_ {
+            VIRTIO_BLK_T_IN => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            _ => VIRTQ_DESC_F_NEXT,
+        };
+        let status = self.status() as *mut u8;
+        unsafe {
+            write_volatile(self.header() as *mut RequestHeader, header);
+            write_volatile(status, u8::MAX);
+            // a request is the only one in the queue, and starts at descriptor 0
+            let descs = core::iter::once((
+                self.header(),
+                core::mem::size_of::<RequestHeader>(),
+                VIRTQ_DESC_F_NEXT,
+            ))
+            .chain(segments.iter().map(|&(addr, len)| (addr, len, data_flags)))
+            .chain(core::iter::once((self.status(), 1, VIRTQ_DESC_F_WRITE)));
+            for (i, (addr, len, flags)) in descs.enumerate() {
+                write_volatile(
+                    self.descriptor(i),
+                    Descriptor {
+                        addr: addr as u64,
+                        len: len as u32,
+                        flags,
+                        next: if flags & VIRTQ_DESC_F_NEXT != 0 {
+                            i as u16 + 1
+                        } else {
+                            0
+                        },
+                    },
+                );
+            }
+            let avail = self.avail_ring();
+            write_volatile(&mut (*avail).ring[self.avail_idx as usize % QUEUE_SIZE], 0);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            write_volatile(&mut (*avail).idx, self.avail_idx);
+            fence(Ordering::SeqCst);
+            write_reg(QUEUE_NOTIFY, 0);
+            while read_volatile(&(*self.used_ring()).idx) == self.last_used {
+                core::hint::spin_loop();
+            }
+            fence(Ordering::SeqCst);
+            self.last_used = self.last_used.wrapping_add(1);
+            match read_volatile(status) {
+                VIRTIO_BLK_S_OK => Ok(()),
+                _ => Err(FsError::Io),
+            }
+        }
+    }
+    /// Transfer a block through the bounce buffer, whose single segment
+    /// any device takes
+    fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        self.request(
+            VIRTIO_BLK_T_IN,
+            block_id,
+            &[(self.bounce_buffer(), BLOCK_SZ)],
+        )?;
+        let bounce =
+            unsafe { core::slice::from_raw_parts(self.bounce_buffer() as *const u8, BLOCK_SZ) };
+        buf.copy_from_slice(bounce);
+        Ok(())
+    }
+    fn write_block(&mut self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        let bounce =
+            unsafe { core::slice::from_raw_parts_mut(self.bounce_buffer() as *mut u8, BLOCK_SZ) };
+        bounce.copy_from_slice(buf);
+        self.request(
+            VIRTIO_BLK_T_OUT,
+            block_id,
+            &[(self.bounce_buffer(), BLOCK_SZ)],
+        )
+    }
+    /// Transfer as many whole blocks from the start of the buffer at `addr`
+    /// of `len` bytes as a request takes, return how many bytes, 0 if not
+    /// even a block fits in the segments of a request
+    fn request_blocks(
+        &mut self,
+        type_: u32,
+        block_id: usize,
+        addr: usize,
+        len: usize,
+    ) -> FsResult<usize> {
+        let mut segments = segments(addr, len, self.max_segments);
+        let total: usize = segments.iter().map(|&(_, len)| len).sum();
+        // the last block may be cut by the segment limit
+        let mut excess = total % BLOCK_SZ;
+        while excess > 0 {
+            let last = segments.last_mut().unwrap();
+            let cut = excess.min(last.1);
+            last.1 -= cut;
+            excess -= cut;
+            if last.1 == 0 {
+                segments.pop();
+            }
+        }
+        if segments.is_empty() {
+            return Ok(0);
+        }
+        self.request(type_, block_id, &segments)?;
+        Ok(total - total % BLOCK_SZ)
+    }
+}
+
+/// Physical pieces of the kernel buffer at `addr` of `len` bytes, the
+/// contiguous ones merged, at most `max` of them from its start
+fn segments(addr: usize, len: usize, max: usize) -> Vec<(usize, usize)> {
+    let kernel_space = KERNEL_SPACE.exclusive_access();
+    let mut segments: Vec<(usize, usize)> = Vec::new();
+    let (mut va, end) = (addr, addr + len);
+    while va < end {
+        let piece_end = end.min((va / PAGE_SIZE + 1) * PAGE_SIZE);
+        let pte = kernel_space
+            .translate(VirtAddr::from(va).floor())
+            .expect("virtio buffer is not mapped");
+        let pa = PhysAddr::from(pte.ppn()).0 + VirtAddr::from(va).page_offset();
+        let count = segments.len();
+        match segments.last_mut() {
+            Some((last, last_len)) if *last + *last_len == pa => *last_len += piece_end - va,
+            _ if count == max => break,
+            _ => segments.push((pa, piece_end - va)),
+        }
+        va = piece_end;
+    }
+    segments
+}
+
+pub struct VirtIOBlock(SpinLock<VirtIOBlk>);
+
+impl BlockDevice for VirtIOBlock {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        self.0.exclusive_access().read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        self.0.exclusive_access().write_block(block_id, buf)
+    }
+    /// The range is transferred in as few requests as the segment limit
+    /// allows, and block by block if one of them fails
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> FsResult<()> {
+        let mut blk = self.0.exclusive_access();
+        let mut done = 0;
+        while done < buf.len() {
+            let rest = &mut buf[done..];
+            let id = block_id + done / BLOCK_SZ;
+            let len = match blk.request_blocks(
+                VIRTIO_BLK_T_IN,
+                id,
+                rest.as_mut_ptr() as usize,
+                rest.len(),
+            ) {
+                Ok(len) if len > 0 => len,
+                _ => {
+                    blk.read_block(id, &mut rest[..BLOCK_SZ])?;
+                    BLOCK_SZ
+                }
+            };
+            done += len;
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> FsResult<()> {
+        let mut blk = self.0.exclusive_access();
+        let mut done = 0;
+        while done < buf.len() {
+            let rest = &buf[done..];
+            let id = block_id + done / BLOCK_SZ;
+            let len = match blk.request_blocks(
+                VIRTIO_BLK_T_OUT,
+                id,
+                rest.as_ptr() as usize,
+                rest.len(),
+            ) {
+                Ok(len) if len > 0 => len,
+                _ => {
+                    blk.write_block(id, &rest[..BLOCK_SZ])?;
+                    BLOCK_SZ
+                }
+            };
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl VirtIOBlock {
+    pub fn new() -> Self {
+        Self(SpinLock::new(VirtIOBlk::new()))
+    }
+}
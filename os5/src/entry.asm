@@ -1,12 +1,27 @@
     .section .text.entry
     .globl _start
 _start:
+    # a0 = hartid, which is kept in tp
+    mv tp, a0
+    # every hart has its own boot stack of 4096 * 16 bytes
     la sp, boot_stack_top
+    slli t0, a0, 16
+    sub sp, sp, t0
     call rust_main
 
+    # the other harts are started here through SBI HSM
+    .globl _start_secondary
+_start_secondary:
+    mv tp, a0
+    la sp, boot_stack_top
+    slli t0, a0, 16
+    sub sp, sp, t0
+    call rust_main_secondary
+
     .section .bss.stack
     .globl boot_stack
 boot_stack:
-    .space 4096 * 16
+    # MAX_HARTS boot stacks
+    .space 4096 * 16 * 8
     .globl boot_stack_top
 boot_stack_top:
\ No newline at end of file
@@ -0,0 +1,57 @@
+//! Error numbers of system calls
+//!
+//! Syscall handlers return a [`SysResult`], and [`crate::syscall::syscall()`]
+//! hands an error back to user space as its negated Linux errno value.
+
+/// Linux-compatible error numbers
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(isize)]
+pub enum SysError {
+    /// No such file or directory
+    ENOENT = 2,
+    /// Input/output error
+    EIO = 5,
+    /// Argument list too long
+    E2BIG = 7,
+    /// Exec format error
+    ENOEXEC = 8,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// No child processes
+    ECHILD = 10,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Bad address
+    EFAULT = 14,
+    /// File exists
+    EEXIST = 17,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// No space left on device
+    ENOSPC = 28,
+    /// Broken pipe
+    EPIPE = 32,
+    /// File name too long
+    ENAMETOOLONG = 36,
+    /// Function not implemented
+    ENOSYS = 38,
+    /// Directory not empty
+    ENOTEMPTY = 39,
+}
+
+impl SysError {
+    /// The value returned to user space
+    pub fn as_isize(self) -> isize {
+        -(self as isize)
+    }
+}
+
+/// Result of a syscall handler
+pub type SysResult = Result<usize, SysError>;
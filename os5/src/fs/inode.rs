@@ -0,0 +1,54 @@
+//! The root directory of easy-fs
+
+use crate::drivers::BLOCK_DEVICE;
+use crate::errno::SysError;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use easy_fs::{EasyFileSystem, FsError, Inode};
+use lazy_static::*;
+
+lazy_static! {
+    /// Root directory of the file system, mounted on first use
+    pub static ref ROOT_INODE: Arc<Inode> = {
+        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone()).expect("no easy-fs on the block device");
+        Arc::new(EasyFileSystem::root_inode(&efs))
+    };
+}
+
+impl From<FsError> for SysError {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NoSpace => SysError::ENOSPC,
+            FsError::NotFound => SysError::ENOENT,
+            FsError::NotDir => SysError::ENOTDIR,
+            FsError::IsDir => SysError::EISDIR,
+            FsError::Exists => SysError::EEXIST,
+            FsError::NameTooLong => SysError::ENAMETOOLONG,
+            FsError::NotEmpty => SysError::ENOTEMPTY,
+            FsError::Invalid => SysError::EINVAL,
+            FsError::Corrupted | FsError::Io => SysError::EIO,
+            FsError::TooManyBlocks => SysError::ENOMEM,
+        }
+    }
+}
+
+/// Read the whole ELF file of the application `name`
+///
+/// The file is read at once, so that its contiguous blocks are read from
+/// the disk together.
+pub fn read_app(name: &str) -> Result<Vec<u8>, SysError> {
+    let inode = ROOT_INODE.find(name)?;
+    let mut data = vec![0u8; inode.stat()?.size as usize];
+    let len = inode.read_at(0, &mut data)?;
+    data.truncate(len);
+    Ok(data)
+}
+
+pub fn list_apps() {
+    println!("/**** APPS ****");
+    for app in ROOT_INODE.ls().expect("the root is a directory") {
+        println!("{}", app);
+    }
+    println!("**************/");
+}
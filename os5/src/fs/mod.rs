@@ -0,0 +1,71 @@
+//! File system of the kernel
+//!
+//! easy-fs is mounted from [`BLOCK_DEVICE`](crate::drivers::BLOCK_DEVICE),
+//! and the applications are files in its root directory.
+//!
+//! Everything a process can hold a file descriptor to implements [`File`].
+
+mod inode;
+mod pipe;
+mod stdio;
+
+pub use inode::{list_apps, read_app, ROOT_INODE};
+pub use pipe::make_pipe;
+pub use stdio::{Stdin, Stdout};
+
+use crate::errno::SysResult;
+use crate::mm::UserSlice;
+
+/// An object behind a file descriptor
+pub trait File: Send + Sync {
+    fn readable(&self) -> bool;
+    fn writable(&self) -> bool;
+    /// Read into `buf`, return the number of bytes read, 0 at the end of file
+    fn read(&self, buf: UserSlice) -> SysResult;
+    /// Write `buf`, return the number of bytes written
+    fn write(&self, buf: UserSlice) -> SysResult;
+    fn stat(&self) -> Stat;
+}
+
+/// Status of a file, laid out as `Stat` of the user library
+#[repr(C)]
+#[derive(Debug)]
+pub struct Stat {
+    /// ID of device containing file
+    pub dev: u64,
+    /// inode number
+    pub ino: u64,
+    /// file type and mode
+    pub mode: StatMode,
+    /// number of hard links
+    pub nlink: u32,
+    /// unused pad
+    pad: [u64; 7],
+}
+
+impl Stat {
+    pub fn new(ino: u64, mode: StatMode, nlink: u32) -> Self {
+        Self {
+            dev: 0,
+            ino,
+            mode,
+            nlink,
+            pad: [0; 7],
+        }
+    }
+}
+
+bitflags! {
+    /// File type bits of [`Stat::mode`]
+    pub struct StatMode: u32 {
+        const NULL  = 0;
+        /// named or anonymous pipe
+        const FIFO  = 0o010000;
+        /// character device
+        const CHR   = 0o020000;
+        /// directory
+        const DIR   = 0o040000;
+        /// ordinary regular file
+        const FILE  = 0o100000;
+    }
+}
@@ -0,0 +1,174 @@
+//! Anonymous pipes
+//!
+//! The two ends of a pipe share a bounded ring buffer. A reader blocks while
+//! the ring buffer is empty and some write end is open, then takes what is
+//! there, up to the size of its buffer. A writer blocks while the ring buffer
+//! is full, until every byte is written or every read end is closed.
+
+use super::{File, Stat, StatMode};
+use crate::errno::{SysError, SysResult};
+use crate::mm::UserSlice;
+use crate::sync::SpinLock;
+use crate::task::WaitQueue;
+use alloc::sync::Arc;
+
+const RING_BUFFER_SIZE: usize = 512;
+
+struct PipeRingBuffer {
+    arr: [u8; RING_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+    read_end_closed: bool,
+    write_end_closed: bool,
+}
+
+impl PipeRingBuffer {
+    fn new() -> Self {
+        Self {
+            arr: [0; RING_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+            read_end_closed: false,
+            write_end_closed: false,
+        }
+    }
+    /// Move bytes from the ring buffer to `buf`, return how many
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len);
+        for byte in buf[..n].iter_mut() {
+            *byte = self.arr[self.head];
+            self.head = (self.head + 1) % RING_BUFFER_SIZE;
+        }
+        self.len -= n;
+        n
+    }
+    /// Move bytes from `buf` to the ring buffer, return how many
+    fn write(&mut self, buf: &[u8]) -> usize {
+        let n = buf.len().min(RING_BUFFER_SIZE - self.len);
+        for &byte in buf[..n].iter() {
+            self.arr[(self.head + self.len) % RING_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+        n
+    }
+}
+
+/// What both ends of a pipe share
+struct PipeInner {
+    buffer: SpinLock<PipeRingBuffer>,
+    /// Readers wait here for data or for the write end to be closed
+    readers: WaitQueue,
+    /// Writers wait here for space or for the read end to be closed
+    writers: WaitQueue,
+}
+
+/// One end of a pipe, closed when the last descriptor to it is dropped
+pub struct Pipe {
+    readable: bool,
+    writable: bool,
+    inner: Arc<PipeInner>,
+}
+
+/// Create a pipe, return its read end and write end
+pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
+    let inner = Arc::new(PipeInner {
+        buffer: SpinLock::new(PipeRingBuffer::new()),
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+    let read_end = Arc::new(Pipe {
+        readable: true,
+        writable: false,
+        inner: inner.clone(),
+    });
+    let write_end = Arc::new(Pipe {
+        readable: false,
+        writable: true,
+        inner,
+    });
+    (read_end, write_end)
+}
+
+impl File for Pipe {
+    fn readable(&self) -> bool {
+        self.readable
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+    fn read(&self, buf: UserSlice) -> SysResult {
+        assert!(self.readable);
+        // user pages may fault in, which is not done with the buffer locked
+        let mut buffers = buf.writable()?;
+        if buffers.iter().all(|buffer| buffer.is_empty()) {
+            return Ok(0);
+        }
+        loop {
+            self.inner.readers.wait_until(|| {
+                let ring = self.inner.buffer.exclusive_access();
+                ring.len > 0 || ring.write_end_closed
+            });
+            let mut ring = self.inner.buffer.exclusive_access();
+            let mut read = 0;
+            for buffer in buffers.iter_mut() {
+                let n = ring.read(buffer);
+                read += n;
+                if n < buffer.len() {
+                    break;
+                }
+            }
+            // another reader may have taken the bytes first
+            if read > 0 || ring.write_end_closed {
+                drop(ring);
+                self.inner.writers.wake_all();
+                return Ok(read);
+            }
+        }
+    }
+    fn write(&self, buf: UserSlice) -> SysResult {
+        assert!(self.writable);
+        let mut written = 0;
+        for buffer in buf.readable()? {
+            let mut copied = 0;
+            while copied < buffer.len() {
+                self.inner.writers.wait_until(|| {
+                    let ring = self.inner.buffer.exclusive_access();
+                    ring.len < RING_BUFFER_SIZE || ring.read_end_closed
+                });
+                let mut ring = self.inner.buffer.exclusive_access();
+                if ring.read_end_closed {
+                    drop(ring);
+                    // a partial write reports what has got through
+                    return match written + copied {
+                        0 => Err(SysError::EPIPE),
+                        n => Ok(n),
+                    };
+                }
+                copied += ring.write(&buffer[copied..]);
+                drop(ring);
+                self.inner.readers.wake_all();
+            }
+            written += copied;
+        }
+        Ok(written)
+    }
+    fn stat(&self) -> Stat {
+        Stat::new(0, StatMode::FIFO, 1)
+    }
+}
+
+impl Drop for Pipe {
+    /// Let the other end see end of file or `EPIPE`
+    fn drop(&mut self) {
+        let mut ring = self.inner.buffer.exclusive_access();
+        if self.readable {
+            ring.read_end_closed = true;
+        }
+        if self.writable {
+            ring.write_end_closed = true;
+        }
+        drop(ring);
+        self.inner.readers.wake_all();
+        self.inner.writers.wake_all();
+    }
+}
@@ -0,0 +1,66 @@
+//! Standard input and output on the SBI console
+
+use super::{File, Stat, StatMode};
+use crate::errno::SysResult;
+use crate::mm::UserSlice;
+use crate::sbi::{console_getchar, console_putchar};
+use crate::task::suspend_current_and_run_next;
+
+/// Standard input, the keyboard of the console
+pub struct Stdin;
+
+/// Standard output and standard error, the screen of the console
+pub struct Stdout;
+
+impl File for Stdin {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        false
+    }
+    /// Read at most one character, waiting until there is one
+    fn read(&self, buf: UserSlice) -> SysResult {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let c = loop {
+            match console_getchar() {
+                0 => suspend_current_and_run_next(),
+                c => break c,
+            }
+        };
+        buf.writable()?[0][0] = c as u8;
+        Ok(1)
+    }
+    fn write(&self, _buf: UserSlice) -> SysResult {
+        unreachable!("stdin is not writable")
+    }
+    fn stat(&self) -> Stat {
+        Stat::new(0, StatMode::CHR, 1)
+    }
+}
+
+impl File for Stdout {
+    fn readable(&self) -> bool {
+        false
+    }
+    fn writable(&self) -> bool {
+        true
+    }
+    fn read(&self, _buf: UserSlice) -> SysResult {
+        unreachable!("stdout is not readable")
+    }
+    fn write(&self, buf: UserSlice) -> SysResult {
+        // a character may be split between two buffers, output raw bytes
+        for buffer in buf.readable()? {
+            for &byte in buffer.iter() {
+                console_putchar(byte as usize);
+            }
+        }
+        Ok(buf.len())
+    }
+    fn stat(&self) -> Stat {
+        Stat::new(0, StatMode::CHR, 1)
+    }
+}
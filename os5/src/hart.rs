@@ -0,0 +1,61 @@
+//! Harts and the operations across them
+//!
+//! The boot hart initializes the kernel and then starts the other harts with
+//! the SBI HSM extension. Every hart keeps its id in `tp`, which is saved in
+//! the trap context while an application runs, since the application may
+//! change it.
+
+use crate::config::{MAX_HARTS, PAGE_SIZE};
+use crate::mm::VirtPageNum;
+use crate::sbi::{hart_start, remote_sfence_vma};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bit mask of the harts which have started
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Id of the current hart
+pub fn hart_id() -> usize {
+    let hart_id;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) hart_id);
+    }
+    hart_id
+}
+
+/// Mark the current hart as started, before it caches any translation
+pub fn set_online() {
+    ONLINE_HARTS.fetch_or(1 << hart_id(), Ordering::AcqRel);
+}
+
+/// Start every hart other than the current one at `_start_secondary`
+///
+/// Harts which do not exist are skipped, as `hart_start` fails on them.
+pub fn start_other_harts() {
+    extern "C" {
+        fn _start_secondary();
+    }
+    for id in (0..MAX_HARTS).filter(|&id| id != hart_id()) {
+        if hart_start(id, _start_secondary as usize, 0) == 0 {
+            info!("start hart {}", id);
+        }
+    }
+}
+
+/// Flush the translations of `[start_vpn, end_vpn)` from the TLB of every hart
+///
+/// A hart keeps using stale translations of an unmapped range until it
+/// flushes them, so the others are asked to do so through the SBI RFENCE
+/// extension before the range may be mapped again.
+pub fn tlb_shootdown(start_vpn: VirtPageNum, end_vpn: VirtPageNum) {
+    let start = start_vpn.0 * PAGE_SIZE;
+    let size = (end_vpn.0 - start_vpn.0) * PAGE_SIZE;
+    for va in (start..start + size).step_by(PAGE_SIZE) {
+        unsafe {
+            core::arch::asm!("sfence.vma {}", in(reg) va);
+        }
+    }
+    let others = ONLINE_HARTS.load(Ordering::Acquire) & !(1 << hart_id());
+    if others != 0 {
+        remote_sfence_vma(others, 0, start, size);
+    }
+}
@@ -13,8 +13,11 @@ extern crate alloc;
 #[macro_use]
 mod console;
 mod config;
+mod drivers;
+mod errno;
+mod fs;
+mod hart;
 mod lang_items;
-mod loader;
 mod logging;
 mod mm;
 mod sbi;
@@ -25,7 +28,6 @@ mod timer;
 mod trap;
 
 core::arch::global_asm!(include_str!("entry.asm"));
-core::arch::global_asm!(include_str!("link_app.S"));
 
 fn clear_bss() {
     extern "C" {
@@ -39,8 +41,10 @@ fn clear_bss() {
 }
 
 #[no_mangle]
-pub fn rust_main() -> ! {
+pub fn rust_main(hart_id: usize) -> ! {
+    assert!(hart_id < config::MAX_HARTS);
     clear_bss();
+    hart::set_online();
     logging::init();
     println!("[kernel] Hello, world!");
     mm::init();
@@ -50,7 +54,20 @@ pub fn rust_main() -> ! {
     trap::init();
     trap::enable_timer_interrupt();
     timer::set_next_trigger();
-    loader::list_apps();
+    fs::list_apps();
+    hart::start_other_harts();
     task::run_tasks();
     panic!("Unreachable in rust_main!");
 }
+
+/// Entry of the other harts, after the boot hart has initialized the kernel
+#[no_mangle]
+pub fn rust_main_secondary() -> ! {
+    hart::set_online();
+    mm::init_hart();
+    trap::init();
+    trap::enable_timer_interrupt();
+    timer::set_next_trigger();
+    task::run_tasks();
+    panic!("Unreachable in rust_main_secondary!");
+}
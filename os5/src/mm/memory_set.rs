@@ -4,11 +4,15 @@ use super::{frame_alloc, FrameTracker};
 use super::{PTEFlags, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
-use crate::config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
-use crate::sync::UPSafeCell;
+use crate::config::{MEMORY_END, MMIO, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
+use crate::errno::SysError;
+use crate::hart::tlb_shootdown;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::mem::size_of;
 use lazy_static::*;
 use riscv::register::satp;
 
@@ -27,8 +31,8 @@ extern "C" {
 
 lazy_static! {
     /// a memory set instance through lazy_static! managing kernel space
-    pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> =
-        Arc::new(unsafe { UPSafeCell::new(MemorySet::new_kernel()) });
+    pub static ref KERNEL_SPACE: Arc<SpinLock<MemorySet>> =
+        Arc::new(SpinLock::new(MemorySet::new_kernel()));
 }
 
 /// memory set structure, controls virtual-memory space
@@ -38,11 +42,11 @@ pub struct MemorySet {
 }
 
 impl MemorySet {
-    pub fn new_bare() -> Self {
-        Self {
-            page_table: PageTable::new(),
+    pub fn new_bare() -> Result<Self, SysError> {
+        Ok(Self {
+            page_table: PageTable::new()?,
             areas: Vec::new(),
-        }
+        })
     }
     pub fn token(&self) -> usize {
         self.page_table.token()
@@ -53,11 +57,40 @@ impl MemorySet {
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
+    ) -> Result<(), SysError> {
         self.push(
             MapArea::new(start_va, end_va, MapType::Framed, permission),
             None,
-        );
+        )
+    }
+    /// Like [`MemorySet::insert_framed_area`], but frames are only allocated
+    /// when the pages are first accessed.
+    ///
+    /// The new area is merged into adjacent lazy areas with the same permission.
+    /// Assume that no conflicts.
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) {
+        let mut new_area = MapArea::new(start_va, end_va, MapType::Lazy, permission);
+        // absorb the following area
+        if let Some(idx) = self.areas.iter().position(|area| {
+            area.vpn_range.get_start() == new_area.vpn_range.get_end() && new_area.can_merge(area)
+        }) {
+            let next = self.areas.remove(idx);
+            new_area.merge(next);
+        }
+        // and join the preceding one
+        if let Some(prev) = self.areas.iter_mut().find(|area| {
+            area.vpn_range.get_end() == new_area.vpn_range.get_start() && area.can_merge(&new_area)
+        }) {
+            prev.merge(new_area);
+            return;
+        }
+        // a lazy area maps nothing yet
+        self.areas.push(new_area);
     }
     pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
         if let Some((idx, area)) = self
@@ -67,29 +100,31 @@ impl MemorySet {
             .find(|(_, area)| area.vpn_range.get_start() == start_vpn)
         {
             area.unmap(&mut self.page_table);
+            tlb_shootdown(area.vpn_range.get_start(), area.vpn_range.get_end());
             self.areas.remove(idx);
         }
     }
-    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> Result<(), SysError> {
+        map_area.map(&mut self.page_table)?;
         if let Some(data) = data {
             map_area.copy_data(&mut self.page_table, data);
         }
         self.areas.push(map_area);
+        Ok(())
     }
     /// Mention that trampoline is not collected by areas.
-    fn map_trampoline(&mut self) {
+    fn map_trampoline(&mut self) -> Result<(), SysError> {
         self.page_table.map(
             VirtAddr::from(TRAMPOLINE).into(),
             PhysAddr::from(strampoline as usize).into(),
             PTEFlags::R | PTEFlags::X,
-        );
+        )
     }
     /// Without kernel stacks.
     pub fn new_kernel() -> Self {
-        let mut memory_set = Self::new_bare();
+        let mut memory_set = Self::new_bare().unwrap();
         // map trampoline
-        memory_set.map_trampoline();
+        memory_set.map_trampoline().unwrap();
         // map kernel sections
         info!(".text [{:#x}, {:#x})", stext as usize, etext as usize);
         info!(".rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
@@ -99,75 +134,114 @@ impl MemorySet {
             sbss_with_stack as usize, ebss as usize
         );
         info!("mapping .text section");
-        memory_set.push(
-            MapArea::new(
-                (stext as usize).into(),
-                (etext as usize).into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::X,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (stext as usize).into(),
+                    (etext as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::X,
+                ),
+                None,
+            )
+            .unwrap();
         info!("mapping .rodata section");
-        memory_set.push(
-            MapArea::new(
-                (srodata as usize).into(),
-                (erodata as usize).into(),
-                MapType::Identical,
-                MapPermission::R,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (srodata as usize).into(),
+                    (erodata as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R,
+                ),
+                None,
+            )
+            .unwrap();
         info!("mapping .data section");
-        memory_set.push(
-            MapArea::new(
-                (sdata as usize).into(),
-                (edata as usize).into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (sdata as usize).into(),
+                    (edata as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            )
+            .unwrap();
         info!("mapping .bss section");
-        memory_set.push(
-            MapArea::new(
-                (sbss_with_stack as usize).into(),
-                (ebss as usize).into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (sbss_with_stack as usize).into(),
+                    (ebss as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            )
+            .unwrap();
         info!("mapping physical memory");
-        memory_set.push(
-            MapArea::new(
-                (ekernel as usize).into(),
-                MEMORY_END.into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (ekernel as usize).into(),
+                    MEMORY_END.into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            )
+            .unwrap();
+        info!("mapping memory-mapped registers");
+        for &(start, len) in MMIO {
+            memory_set
+                .push(
+                    MapArea::new(
+                        start.into(),
+                        (start + len).into(),
+                        MapType::Identical,
+                        MapPermission::R | MapPermission::W,
+                    ),
+                    None,
+                )
+                .unwrap();
+        }
         memory_set
     }
     /// Include sections in elf and trampoline and TrapContext and user stack,
     /// also returns user_sp and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut memory_set = Self::new_bare();
+    ///
+    /// Fail with `ENOEXEC` if `elf_data` is not a valid elf of a user program.
+    pub fn from_elf(elf_data: &[u8]) -> Result<(Self, usize, usize), SysError> {
+        let mut memory_set = Self::new_bare()?;
         // map trampoline
-        memory_set.map_trampoline();
+        memory_set.map_trampoline()?;
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf = xmas_elf::ElfFile::new(elf_data).map_err(|_| SysError::ENOEXEC)?;
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            return Err(SysError::ENOEXEC);
+        }
         let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
         for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+            let ph = elf.program_header(i).map_err(|_| SysError::ENOEXEC)?;
+            if ph.get_type().map_err(|_| SysError::ENOEXEC)? == xmas_elf::program::Type::Load {
+                let end = ph
+                    .virtual_addr()
+                    .checked_add(ph.mem_size())
+                    .filter(|&end| end <= TRAP_CONTEXT as u64)
+                    .ok_or(SysError::ENOEXEC)?;
                 let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
+                let end_va: VirtAddr = (end as usize).into();
+                let data = ph
+                    .offset()
+                    .checked_add(ph.file_size())
+                    .filter(|_| ph.file_size() <= ph.mem_size())
+                    .and_then(|data_end| elf.input.get(ph.offset() as usize..data_end as usize))
+                    .ok_or(SysError::ENOEXEC)?;
                 let mut map_perm = MapPermission::U;
                 let ph_flags = ph.flags();
                 if ph_flags.is_read() {
@@ -180,11 +254,13 @@ impl MemorySet {
                     map_perm |= MapPermission::X;
                 }
                 let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
-                max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                // segments sharing a page can not be mapped with their own permissions
+                if memory_set.overlaps(map_area.vpn_range.get_start(), map_area.vpn_range.get_end())
+                {
+                    return Err(SysError::ENOEXEC);
+                }
+                max_end_vpn = max_end_vpn.max(map_area.vpn_range.get_end());
+                memory_set.push(map_area, Some(data))?;
             }
         }
         // map user stack with U flags
@@ -193,15 +269,18 @@ impl MemorySet {
         // guard page
         user_stack_bottom += PAGE_SIZE;
         let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
+        if user_stack_top > TRAP_CONTEXT {
+            return Err(SysError::ENOEXEC);
+        }
         memory_set.push(
             MapArea::new(
                 user_stack_bottom.into(),
                 user_stack_top.into(),
-                MapType::Framed,
+                MapType::Lazy,
                 MapPermission::R | MapPermission::W | MapPermission::U,
             ),
             None,
-        );
+        )?;
         // map TrapContext
         memory_set.push(
             MapArea::new(
@@ -211,32 +290,104 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
-        (
+        )?;
+        Ok((
             memory_set,
             user_stack_top,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
+    }
+    /// Push the arguments of a new program onto its user stack below
+    /// `user_sp`, return the new user_sp and the address of argv.
+    ///
+    /// argv is a NULL-terminated array of pointers right below `user_sp`, and
+    /// the nul-terminated strings it points to are placed below argv.
+    pub fn push_args(
+        &mut self,
+        user_sp: usize,
+        args: &[String],
+    ) -> Result<(usize, usize), SysError> {
+        let argv_base = user_sp - (args.len() + 1) * size_of::<usize>();
+        let mut argv: Vec<usize> = Vec::with_capacity(args.len() + 1);
+        let mut sp = argv_base;
+        for arg in args {
+            sp -= arg.len() + 1;
+            self.copy_to_user(sp, arg.as_bytes())?;
+            self.copy_to_user(sp + arg.len(), &[0])?;
+            argv.push(sp);
+        }
+        argv.push(0);
+        let argv_bytes = unsafe {
+            core::slice::from_raw_parts(argv.as_ptr() as *const u8, argv.len() * size_of::<usize>())
+        };
+        self.copy_to_user(argv_base, argv_bytes)?;
+        // the stack pointer is always 16-byte aligned
+        Ok((sp & !0xf, argv_base))
+    }
+    /// Copy `data` to `va` of this user space, which need not be active
+    ///
+    /// The pages are on the user stack, so a page fault only fails for want
+    /// of a frame.
+    fn copy_to_user(&mut self, va: usize, data: &[u8]) -> Result<(), SysError> {
+        let mut va = VirtAddr::from(va);
+        let mut data = data;
+        while !data.is_empty() {
+            let vpn = va.floor();
+            self.handle_page_fault(vpn, true);
+            let ppn = self
+                .translate(vpn)
+                .filter(|pte| pte.is_valid())
+                .ok_or(SysError::ENOMEM)?
+                .ppn();
+            let len = data.len().min(PAGE_SIZE - va.page_offset());
+            ppn.get_bytes_array()[va.page_offset()..va.page_offset() + len]
+                .copy_from_slice(&data[..len]);
+            data = &data[len..];
+            va = VirtAddr::from(va.0 + len);
+        }
+        Ok(())
     }
     /// Copy an identical user_space
-    pub fn from_existed_user(user_space: &MemorySet) -> MemorySet {
-        let mut memory_set = Self::new_bare();
+    ///
+    /// Data frames are shared with `user_space` instead of being copied:
+    /// writable pages become read-only in both spaces and are copied on the
+    /// first store (see [`MemorySet::handle_page_fault`]). The trap context is
+    /// written by the kernel directly, so it is still copied eagerly.
+    pub fn from_existed_user(user_space: &mut MemorySet) -> Result<MemorySet, SysError> {
+        let mut memory_set = Self::new_bare()?;
         // map trampoline
-        memory_set.map_trampoline();
-        // copy data sections/trap_context/user_stack
+        memory_set.map_trampoline()?;
+        let trap_cx_vpn: VirtPageNum = VirtAddr::from(TRAP_CONTEXT).into();
+        // share data sections/user_stack, copy trap_context
         for area in user_space.areas.iter() {
-            let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
-            // copy data from another space
-            for vpn in area.vpn_range {
-                let src_ppn = user_space.translate(vpn).unwrap().ppn();
-                let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
-                dst_ppn
-                    .get_bytes_array()
-                    .copy_from_slice(src_ppn.get_bytes_array());
+            if area.vpn_range.get_start() == trap_cx_vpn {
+                memory_set.push(MapArea::from_another(area), None)?;
+                for vpn in area.vpn_range {
+                    let src_ppn = user_space.translate(vpn).unwrap().ppn();
+                    let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
+                    dst_ppn
+                        .get_bytes_array()
+                        .copy_from_slice(src_ppn.get_bytes_array());
+                }
+                continue;
             }
+            let mut new_area = MapArea::from_another(area);
+            let pte_flags = area.shared_pte_flags();
+            for (vpn, frame) in area.data_frames.iter() {
+                if !user_space
+                    .translate(*vpn)
+                    .map_or(false, |pte| pte.is_valid())
+                {
+                    continue;
+                }
+                // if the child fails, the parent takes the frame back on a store
+                user_space.page_table.remap(*vpn, frame.ppn, pte_flags);
+                memory_set.page_table.map(*vpn, frame.ppn, pte_flags)?;
+                new_area.data_frames.insert(*vpn, Arc::clone(frame));
+            }
+            memory_set.areas.push(new_area);
         }
-        memory_set
+        Ok(memory_set)
     }
     pub fn activate(&self) {
         let satp = self.page_table.token();
@@ -248,22 +399,77 @@ impl MemorySet {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
+    /// Try to resolve a page fault at `vpn`, return whether the faulting
+    /// access can be retried.
+    ///
+    /// Untouched pages of lazy areas get a zeroed frame, and stores to
+    /// copy-on-write pages get a private writable copy of the frame.
+    pub fn handle_page_fault(&mut self, vpn: VirtPageNum, is_store: bool) -> bool {
+        let page_table = &mut self.page_table;
+        match self.areas.iter_mut().find(|area| area.contains(vpn)) {
+            Some(area)
+                if area.map_type == MapType::Lazy && !area.data_frames.contains_key(&vpn) =>
+            {
+                area.map_lazy(page_table, vpn, is_store)
+            }
+            Some(area) if is_store => area.copy_on_write(page_table, vpn),
+            _ => false,
+        }
+    }
+    /// Whether any page in `[start_vpn, end_vpn)` belongs to an area
+    pub fn overlaps(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        self.areas.iter().any(|area| {
+            area.vpn_range.get_start() < end_vpn && start_vpn < area.vpn_range.get_end()
+        })
+    }
+    /// Whether every page in `[start_vpn, end_vpn)` belongs to an area
+    pub fn covers(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        VPNRange::new(start_vpn, end_vpn)
+            .into_iter()
+            .all(|vpn| self.areas.iter().any(|area| area.contains(vpn)))
+    }
     pub fn recycle_data_pages(&mut self) {
         //*self = Self::new_bare();
         self.areas.clear();
     }
 
+    /// Unmap `[start_vpn, end_vpn)` and free its frames
+    ///
+    /// Areas entirely inside the range are removed, areas overlapping one end
+    /// are shrunk and an area containing the whole range is split in two.
     pub fn unmap(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) {
-        for vpn in VPNRange::new(start_vpn, end_vpn) {
-            self.page_table.unmap(vpn);
+        let mut split_areas = Vec::new();
+        for area in self.areas.iter_mut() {
+            let area_start = area.vpn_range.get_start();
+            let area_end = area.vpn_range.get_end();
+            let start = start_vpn.max(area_start);
+            let end = end_vpn.min(area_end);
+            if start >= end {
+                continue;
+            }
+            for vpn in VPNRange::new(start, end) {
+                area.unmap_one(&mut self.page_table, vpn);
+            }
+            if area_start < start && end < area_end {
+                split_areas.push(area.split_off(end));
+            }
+            if area_start < start {
+                area.vpn_range = VPNRange::new(area_start, start);
+            } else {
+                area.vpn_range = VPNRange::new(end.max(area_start), area_end);
+            }
         }
+        self.areas.retain(|area| !area.is_empty());
+        self.areas.extend(split_areas);
+        tlb_shootdown(start_vpn, end_vpn);
     }
 }
 
 /// map area structure, controls a contiguous piece of virtual memory
 pub struct MapArea {
     vpn_range: VPNRange,
-    data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    /// frames may be shared with other address spaces after fork
+    data_frames: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     map_type: MapType,
     map_perm: MapPermission,
 }
@@ -292,36 +498,138 @@ impl MapArea {
             map_perm: another.map_perm,
         }
     }
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        let ppn: PhysPageNum;
+    pub fn map_one(
+        &mut self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+    ) -> Result<(), SysError> {
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
         match self.map_type {
-            MapType::Identical => {
-                ppn = PhysPageNum(vpn.0);
-            }
-            MapType::Framed => {
-                let frame = frame_alloc().unwrap();
-                ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+            MapType::Identical => page_table.map(vpn, PhysPageNum(vpn.0), pte_flags),
+            MapType::Framed | MapType::Lazy => {
+                let frame = frame_alloc().ok_or(SysError::ENOMEM)?;
+                page_table.map(vpn, frame.ppn, pte_flags)?;
+                self.data_frames.insert(vpn, Arc::new(frame));
+                Ok(())
             }
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        page_table.map(vpn, ppn, pte_flags);
     }
 
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        #[allow(clippy::single_match)]
         match self.map_type {
             MapType::Framed => {
                 self.data_frames.remove(&vpn);
             }
+            MapType::Lazy => {
+                // untouched pages have never been mapped
+                if self.data_frames.remove(&vpn).is_none() {
+                    return;
+                }
+            }
             _ => {}
         }
         page_table.unmap(vpn);
     }
-    pub fn map(&mut self, page_table: &mut PageTable) {
+    /// Split the area at `vpn`, pages from `vpn` on are moved to the returned area.
+    pub fn split_off(&mut self, vpn: VirtPageNum) -> Self {
+        let end = self.vpn_range.get_end();
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), vpn);
+        Self {
+            vpn_range: VPNRange::new(vpn, end),
+            data_frames: self.data_frames.split_off(&vpn),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+        }
+    }
+    /// Whether `another`, which starts right after this area, can be merged
+    pub fn can_merge(&self, another: &MapArea) -> bool {
+        self.map_type == MapType::Lazy
+            && another.map_type == MapType::Lazy
+            && self.map_perm == another.map_perm
+    }
+    /// Append the area starting right after this one
+    pub fn merge(&mut self, mut another: MapArea) {
+        assert_eq!(self.vpn_range.get_end(), another.vpn_range.get_start());
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), another.vpn_range.get_end());
+        self.data_frames.append(&mut another.data_frames);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.vpn_range.get_start() == self.vpn_range.get_end()
+    }
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.vpn_range.get_start() <= vpn && vpn < self.vpn_range.get_end()
+    }
+    /// PTE flags of frames shared copy-on-write: the area permission without W
+    fn shared_pte_flags(&self) -> PTEFlags {
+        PTEFlags::from_bits((self.map_perm - MapPermission::W).bits).unwrap()
+    }
+    /// Allocate the frame of an untouched page in a lazy area, return false
+    /// if the access is not permitted.
+    fn map_lazy(&mut self, page_table: &mut PageTable, vpn: VirtPageNum, is_store: bool) -> bool {
+        let required = if is_store {
+            MapPermission::R | MapPermission::W
+        } else {
+            MapPermission::R
+        };
+        if !self.map_perm.contains(required) {
+            return false;
+        }
+        let frame = match frame_alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        if page_table.map(vpn, frame.ppn, pte_flags).is_err() {
+            return false;
+        }
+        self.data_frames.insert(vpn, Arc::new(frame));
+        true
+    }
+    /// Give `vpn` a private writable frame if it is a copy-on-write page,
+    /// return false if the store should not be allowed.
+    pub fn copy_on_write(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
+        if self.map_type == MapType::Identical || !self.map_perm.contains(MapPermission::W) {
+            return false;
+        }
+        let shared = page_table
+            .translate(vpn)
+            .map_or(false, |pte| pte.is_valid() && !pte.writable());
+        let frame = match self.data_frames.get(&vpn) {
+            Some(frame) if shared => frame,
+            _ => return false,
+        };
+        if Arc::strong_count(frame) > 1 {
+            // still shared, copy it
+            let new_frame = match frame_alloc() {
+                Some(new_frame) => new_frame,
+                None => return false,
+            };
+            new_frame
+                .ppn
+                .get_bytes_array()
+                .copy_from_slice(frame.ppn.get_bytes_array());
+            self.data_frames.insert(vpn, Arc::new(new_frame));
+        }
+        // the last owner simply takes the frame back
+        let ppn = self.data_frames[&vpn].ppn;
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        page_table.remap(vpn, ppn, pte_flags);
+        true
+    }
+    /// Map every page of a non-lazy area, nothing is mapped if it fails
+    pub fn map(&mut self, page_table: &mut PageTable) -> Result<(), SysError> {
+        if self.map_type == MapType::Lazy {
+            return Ok(());
+        }
         for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+            if let Err(err) = self.map_one(page_table, vpn) {
+                for mapped in VPNRange::new(self.vpn_range.get_start(), vpn) {
+                    self.unmap_one(page_table, mapped);
+                }
+                return Err(err);
+            }
         }
+        Ok(())
     }
     pub fn unmap(&mut self, page_table: &mut PageTable) {
         for vpn in self.vpn_range {
@@ -353,10 +661,12 @@ impl MapArea {
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
-/// map type for memory set: identical or framed
+/// map type for memory set: identical, framed or lazily framed
 pub enum MapType {
     Identical,
     Framed,
+    /// framed, but each frame is allocated on the first access to its page
+    Lazy,
 }
 
 bitflags! {
@@ -12,14 +12,16 @@ mod frame_allocator;
 mod heap_allocator;
 mod memory_set;
 mod page_table;
+mod user_ptr;
 
 pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 pub use address::{StepByOne, VPNRange};
 pub use frame_allocator::{frame_alloc, FrameTracker};
 pub use memory_set::remap_test;
 pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE};
-pub use page_table::{translated_byte_buffer, translated_refmut, translated_str, PageTableEntry};
+pub use page_table::PageTableEntry;
 use page_table::{PTEFlags, PageTable};
+pub use user_ptr::{UserCStr, UserPtr, UserSlice};
 
 /// initiate heap allocator, frame allocator and kernel space
 pub fn init() {
@@ -27,3 +29,8 @@ pub fn init() {
     frame_allocator::init_frame_allocator();
     KERNEL_SPACE.exclusive_access().activate();
 }
+
+/// Switch to kernel space on a hart other than the boot hart
+pub fn init_hart() {
+    KERNEL_SPACE.exclusive_access().activate();
+}
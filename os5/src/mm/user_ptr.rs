@@ -0,0 +1,182 @@
+//! Checked access to user memory: [`UserPtr`], [`UserSlice`] and [`UserCStr`].
+//!
+//! The kernel reaches user memory through the identical mapping of physical
+//! memory, which bypasses the permission bits of the user page table. So every
+//! page is looked up in the user page table first, and it must be valid,
+//! accessible to the user, and readable or writable as the access requires.
+//! A lazy or copy-on-write page is resolved the same way as a page fault of the
+//! user would be. Any other address makes the syscall fail with `EFAULT`.
+
+use super::{PTEFlags, PageTable, StepByOne, VirtAddr, VirtPageNum};
+use crate::errno::SysError;
+use crate::task::task_page_fault;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+
+/// Longest string accepted from user space, including the terminating nul
+pub const MAX_CSTR_LEN: usize = 4096;
+
+/// Bytes of the user page `vpn`, checked for a load or a store of the user
+fn user_page(
+    page_table: &PageTable,
+    vpn: VirtPageNum,
+    is_store: bool,
+) -> Result<&'static mut [u8], SysError> {
+    let required = PTEFlags::V | PTEFlags::U | if is_store { PTEFlags::W } else { PTEFlags::R };
+    let translate = || {
+        page_table
+            .translate(vpn)
+            .filter(|pte| pte.flags().contains(required))
+            .map(|pte| pte.ppn().get_bytes_array())
+    };
+    if let Some(bytes) = translate() {
+        return Ok(bytes);
+    }
+    let va: VirtAddr = vpn.into();
+    if task_page_fault(va.into(), is_store) {
+        translate().ok_or(SysError::EFAULT)
+    } else {
+        Err(SysError::EFAULT)
+    }
+}
+
+/// A byte range in user space
+pub struct UserSlice {
+    token: usize,
+    start: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(token: usize, ptr: *const u8, len: usize) -> Self {
+        Self {
+            token,
+            start: ptr as usize,
+            len,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Split the range into pieces which do not cross a page
+    fn buffers(&self, is_store: bool) -> Result<Vec<&'static mut [u8]>, SysError> {
+        let page_table = PageTable::from_token(self.token);
+        let mut start = self.start;
+        let end = start.checked_add(self.len).ok_or(SysError::EFAULT)?;
+        let mut v = Vec::new();
+        while start < end {
+            let start_va = VirtAddr::from(start);
+            let mut vpn = start_va.floor();
+            let bytes = user_page(&page_table, vpn, is_store)?;
+            vpn.step();
+            let end_va = VirtAddr::from(vpn).min(VirtAddr::from(end));
+            let page_end = start_va.page_offset() + (end_va.0 - start_va.0);
+            v.push(&mut bytes[start_va.page_offset()..page_end]);
+            start = end_va.into();
+        }
+        Ok(v)
+    }
+    /// Pieces of the range which the user is allowed to read
+    pub fn readable(&self) -> Result<Vec<&'static [u8]>, SysError> {
+        Ok(self
+            .buffers(false)?
+            .into_iter()
+            .map(|buffer| &*buffer)
+            .collect())
+    }
+    /// Pieces of the range which the user is allowed to write
+    pub fn writable(&self) -> Result<Vec<&'static mut [u8]>, SysError> {
+        self.buffers(true)
+    }
+    /// Copy the range into `dst`, which must be as long as the range
+    pub fn copy_to(&self, dst: &mut [u8]) -> Result<(), SysError> {
+        let mut copied = 0;
+        for buffer in self.readable()? {
+            dst[copied..copied + buffer.len()].copy_from_slice(buffer);
+            copied += buffer.len();
+        }
+        Ok(())
+    }
+    /// Fill the range with `src`, which must be as long as the range
+    pub fn copy_from(&self, src: &[u8]) -> Result<(), SysError> {
+        let mut copied = 0;
+        for buffer in self.writable()? {
+            let len = buffer.len();
+            buffer.copy_from_slice(&src[copied..copied + len]);
+            copied += len;
+        }
+        Ok(())
+    }
+}
+
+/// A value of type `T` in user space, which may straddle two pages
+pub struct UserPtr<T> {
+    slice: UserSlice,
+    _marker: PhantomData<T>,
+}
+
+impl<T> UserPtr<T> {
+    pub fn new(token: usize, ptr: *mut T) -> Self {
+        Self {
+            slice: UserSlice::new(token, ptr as *const u8, size_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+    pub fn write(&self, value: T) -> Result<(), SysError> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        self.slice.copy_from(bytes)
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn read(&self) -> Result<T, SysError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        self.slice.copy_to(bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+}
+
+/// A nul-terminated string in user space
+pub struct UserCStr {
+    token: usize,
+    ptr: *const u8,
+}
+
+impl UserCStr {
+    pub fn new(token: usize, ptr: *const u8) -> Self {
+        Self { token, ptr }
+    }
+    /// Read the string, which must be valid UTF-8 and shorter than [`MAX_CSTR_LEN`]
+    pub fn read(&self) -> Result<String, SysError> {
+        let page_table = PageTable::from_token(self.token);
+        let mut bytes = Vec::new();
+        let mut va = VirtAddr::from(self.ptr as usize);
+        loop {
+            let rest = &user_page(&page_table, va.floor(), false)?[va.page_offset()..];
+            let (len, terminated) = match rest.iter().position(|&ch| ch == 0) {
+                Some(len) => (len, true),
+                None => (rest.len(), false),
+            };
+            if bytes.len() + len >= MAX_CSTR_LEN {
+                return Err(SysError::ENAMETOOLONG);
+            }
+            bytes.extend_from_slice(&rest[..len]);
+            if terminated {
+                break;
+            }
+            let mut vpn = va.floor();
+            vpn.step();
+            va = vpn.into();
+        }
+        String::from_utf8(bytes).map_err(|_| SysError::EINVAL)
+    }
+}
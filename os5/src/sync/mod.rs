@@ -1,5 +1,7 @@
 //! Synchronization and interior mutability primitives
 
+mod spin;
 mod up;
 
+pub use self::spin::{SpinLock, SpinLockGuard};
 pub use up::UPSafeCell;
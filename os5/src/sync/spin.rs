@@ -0,0 +1,70 @@
+//! Multiprocessor mutual exclusion primitives
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Wrap a static data structure shared between harts, and let one hart
+/// access it at a time.
+///
+/// The kernel runs with interrupts disabled except when a hart is idle, so
+/// the lock is never taken in an interrupt handler while it is held.
+///
+/// In order to get mutable reference of inner data, call
+/// `exclusive_access`, which spins until the lock is free.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    /// inner data
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(value),
+        }
+    }
+    /// Spin until the lock is acquired. Taking it again on the same hart
+    /// before the guard is dropped deadlocks.
+    pub fn exclusive_access(&self) -> SpinLockGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+/// Access to the data of a [`SpinLock`], which is released on drop
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
@@ -1,50 +1,88 @@
 //! File and filesystem-related syscalls
 
-use crate::mm::translated_byte_buffer;
-use crate::sbi::console_getchar;
-use crate::task::{current_user_token, suspend_current_and_run_next};
-
-const FD_STDIN: usize = 0;
-const FD_STDOUT: usize = 1;
-
-pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
-    match fd {
-        FD_STDOUT => {
-            let buffers = translated_byte_buffer(current_user_token(), buf, len);
-            for buffer in buffers {
-                print!("{}", core::str::from_utf8(buffer).unwrap());
-            }
-            len as isize
-        }
-        _ => {
-            panic!("Unsupported fd in sys_write!");
-        }
+use crate::errno::{SysError, SysResult};
+use crate::fs::{make_pipe, File, Stat};
+use crate::mm::{UserPtr, UserSlice};
+use crate::task::{current_task, current_user_token};
+use alloc::sync::Arc;
+
+/// The open file of descriptor `fd` of the current task
+fn current_file(fd: usize) -> Result<Arc<dyn File>, SysError> {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    inner.get_file(fd).ok_or(SysError::EBADF)
+}
+
+pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> SysResult {
+    let file = current_file(fd)?;
+    if !file.writable() {
+        return Err(SysError::EBADF);
     }
+    // the TCB is not locked, the write may block
+    file.write(UserSlice::new(current_user_token(), buf, len))
 }
 
-pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
-    match fd {
-        FD_STDIN => {
-            assert_eq!(len, 1, "Only support len = 1 in sys_read!");
-            let mut c: usize;
-            loop {
-                c = console_getchar();
-                if c == 0 {
-                    suspend_current_and_run_next();
-                    continue;
-                } else {
-                    break;
-                }
-            }
-            let ch = c as u8;
-            let mut buffers = translated_byte_buffer(current_user_token(), buf, len);
-            unsafe {
-                buffers[0].as_mut_ptr().write_volatile(ch);
-            }
-            1
-        }
-        _ => {
-            panic!("Unsupported fd in sys_read!");
+pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> SysResult {
+    let file = current_file(fd)?;
+    if !file.readable() {
+        return Err(SysError::EBADF);
+    }
+    file.read(UserSlice::new(current_user_token(), buf, len))
+}
+
+pub fn sys_close(fd: usize) -> SysResult {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let file = inner
+        .fd_table
+        .get_mut(fd)
+        .and_then(|file| file.take())
+        .ok_or(SysError::EBADF)?;
+    drop(inner);
+    // closing a file may wake up other tasks, so do it without the TCB locked
+    drop(file);
+    Ok(0)
+}
+
+/// Duplicate `fd` to the lowest closed descriptor
+pub fn sys_dup(fd: usize) -> SysResult {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let file = inner.get_file(fd).ok_or(SysError::EBADF)?;
+    let new_fd = inner.alloc_fd()?;
+    inner.fd_table[new_fd] = Some(file);
+    Ok(new_fd)
+}
+
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> SysResult {
+    let stat = current_file(fd)?.stat();
+    UserPtr::new(current_user_token(), st).write(stat)?;
+    Ok(0)
+}
+
+/// Create a pipe, and store the descriptors of its read end and write end
+/// in `pipe`
+pub fn sys_pipe(pipe: *mut [usize; 2]) -> SysResult {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let (read_end, write_end) = make_pipe();
+    let read_fd = inner.alloc_fd()?;
+    inner.fd_table[read_fd] = Some(read_end);
+    let write_fd = match inner.alloc_fd() {
+        Ok(fd) => fd,
+        Err(err) => {
+            // nobody else holds the pipe, so dropping it wakes up no one
+            inner.fd_table[read_fd] = None;
+            return Err(err);
         }
+    };
+    inner.fd_table[write_fd] = Some(write_end);
+    let token = inner.get_user_token();
+    drop(inner);
+    if let Err(err) = UserPtr::new(token, pipe).write([read_fd, write_fd]) {
+        sys_close(read_fd)?;
+        sys_close(write_fd)?;
+        return Err(err);
     }
+    Ok(0)
 }
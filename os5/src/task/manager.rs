@@ -4,59 +4,98 @@
 //! Other CPU process monitoring functions are in Processor.
 
 
+use super::scheduler::{new_scheduler, Scheduler};
 use super::TaskControlBlock;
-use crate::config::BIG_STRIDE;
-use crate::sync::UPSafeCell;
-use alloc::collections::VecDeque;
+use crate::sync::SpinLock;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use lazy_static::*;
 
 pub struct TaskManager {
-    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+    scheduler: Box<dyn Scheduler>,
+    /// Number of tasks in wait queues, which will be added back later
+    blocked: usize,
+    /// Number of tasks fetched by a hart and not switched out yet
+    running: usize,
 }
 
-// YOUR JOB: FIFO->Stride
-/// A simple FIFO scheduler.
+/// Ready processes are kept by the scheduling policy chosen at build time.
 impl TaskManager {
     pub fn new() -> Self {
         Self {
-            ready_queue: VecDeque::new(),
+            scheduler: new_scheduler(),
+            blocked: 0,
+            running: 0,
         }
     }
     /// Add process back to ready queue
     pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_queue.push_back(task);
+        self.scheduler.add(task);
+    }
+    /// Count a process which leaves for a wait queue
+    pub fn block(&mut self, task: &Arc<TaskControlBlock>) {
+        self.blocked += 1;
+        self.scheduler.on_block(task);
+    }
+    /// Add a process back from a wait queue
+    pub fn wake(&mut self, task: Arc<TaskControlBlock>) {
+        self.blocked -= 1;
+        self.scheduler.on_wake(&task);
+        self.add(task);
+    }
+    /// The time slice of the running process is over, return whether to switch
+    pub fn tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        self.scheduler.tick(task)
+    }
+    /// Count a process which has been switched out of a hart
+    ///
+    /// It has been added back or blocked already if it has not exited.
+    pub fn switched_out(&mut self) {
+        self.running -= 1;
+    }
+    /// Whether no process is ready, running or blocked, so none will ever run again
+    pub fn is_empty(&self) -> bool {
+        self.scheduler.is_empty() && self.blocked == 0 && self.running == 0
     }
     /// Take a process out of the ready queue
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        //self.ready_queue.pop_front()
-        if self.ready_queue.is_empty() {
-            return None;
-        }
-        let mut task_pos: usize = 0;
-        for (idx, task) in self.ready_queue.iter().enumerate().skip(1) {
-            if self.ready_queue[task_pos].inner_exclusive_access().pass > task.inner_exclusive_access().pass {
-                task_pos = idx;
-            }
+        let task = self.scheduler.fetch();
+        if task.is_some() {
+            self.running += 1;
         }
-        let task = self.ready_queue.remove(task_pos).unwrap();
-        let mut inner = task.inner_exclusive_access();
-        inner.pass += BIG_STRIDE / inner.priority;
-        drop(inner);
-        Some(task)
+        task
     }
 }
 
 lazy_static! {
     /// TASK_MANAGER instance through lazy_static!
-    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
-        unsafe { UPSafeCell::new(TaskManager::new()) };
+    pub static ref TASK_MANAGER: SpinLock<TaskManager> = SpinLock::new(TaskManager::new());
 }
 
 pub fn add_task(task: Arc<TaskControlBlock>) {
     TASK_MANAGER.exclusive_access().add(task);
 }
 
+pub fn block_task(task: &Arc<TaskControlBlock>) {
+    TASK_MANAGER.exclusive_access().block(task);
+}
+
+pub fn wake_task(task: Arc<TaskControlBlock>) {
+    TASK_MANAGER.exclusive_access().wake(task);
+}
+
+pub fn tick_task(task: &Arc<TaskControlBlock>) -> bool {
+    TASK_MANAGER.exclusive_access().tick(task)
+}
+
+pub fn switched_out_task() {
+    TASK_MANAGER.exclusive_access().switched_out();
+}
+
+pub fn no_task_left() -> bool {
+    TASK_MANAGER.exclusive_access().is_empty()
+}
+
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
     TASK_MANAGER.exclusive_access().fetch()
 }
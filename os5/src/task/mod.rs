@@ -4,7 +4,7 @@
 //! (such as syscall or clock interrupt).
 //! By suspending or exiting the current process, you can
 //! modify the process state, manage the process queue through TASK_MANAGER,
-//! and switch the control flow through PROCESSOR.
+//! and switch the control flow through the Processor of the current hart.
 //!
 //! Be careful when you see [`__switch`]. Control flow around this function
 //! might not be what you expect.
@@ -13,16 +13,19 @@ mod context;
 mod manager;
 mod pid;
 mod processor;
+mod scheduler;
 mod switch;
 #[allow(clippy::module_inception)]
 mod task;
+mod wait_queue;
 
-use crate::loader::get_app_data_by_name;
+use crate::fs::read_app;
 use alloc::sync::Arc;
 use lazy_static::*;
-use manager::fetch_task;
+use manager::{block_task, fetch_task, no_task_left, switched_out_task, tick_task, wake_task};
 use switch::__switch;
-pub use task::{TaskControlBlock, TaskStatus};
+pub use task::{TaskControlBlock, TaskControlBlockInner, TaskStatus};
+pub use wait_queue::WaitQueue;
 
 pub use context::TaskContext;
 pub use manager::add_task;
@@ -36,6 +39,7 @@ pub use processor::{
     take_current_task,
     task_mmap,
     task_munmap,
+    task_page_fault,
     count_syscall,
     current_task_status,
     current_syscall_times,
@@ -61,33 +65,98 @@ pub fn suspend_current_and_run_next() {
     schedule(task_cx_ptr);
 }
 
+/// Account a time slice to the current task, and switch to the next task if
+/// the scheduler preempts it
+pub fn tick_current_and_run_next() {
+    let task = current_task().unwrap();
+    let preempt = tick_task(&task);
+    drop(task);
+    if preempt {
+        suspend_current_and_run_next();
+    }
+}
+
+/// Make current task blocked and switch to the next task
+///
+/// `enqueue` puts the task in a [`WaitQueue`] or the like, which wakes it up
+/// later. It may be woken up on another hart even before switching out.
+pub fn block_current_and_run_next(enqueue: impl FnOnce(Arc<TaskControlBlock>)) {
+    // There must be an application running.
+    let task = take_current_task().unwrap();
+
+    // ---- access current TCB exclusively
+    let mut task_inner = task.inner_exclusive_access();
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    // Change status to Blocked
+    task_inner.task_status = TaskStatus::Blocked;
+    drop(task_inner);
+    // ---- release current PCB
+
+    // count it as blocked before anyone can wake it up
+    block_task(&task);
+    // the wait queue keeps the task alive
+    enqueue(task);
+    // jump to scheduling cycle
+    schedule(task_cx_ptr);
+}
+
+/// Make a blocked task ready and push it back to ready queue
+pub fn wakeup_task(task: Arc<TaskControlBlock>) {
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.task_status = TaskStatus::Ready;
+    drop(task_inner);
+    wake_task(task);
+}
+
 /// Exit current task, recycle process resources and switch to the next task
 pub fn exit_current_and_run_next(exit_code: i32) {
     // take from Processor
     let task = take_current_task().unwrap();
     // **** access current TCB exclusively
     let mut inner = task.inner_exclusive_access();
-    // Change status to Zombie
-    inner.task_status = TaskStatus::Zombie;
     // Record exit code
     inner.exit_code = exit_code;
+    let children = core::mem::take(&mut inner.children);
+    // deallocate user space
+    inner.memory_set.recycle_data_pages();
+    let fd_table = core::mem::take(&mut inner.fd_table);
+    drop(inner);
+    // **** release current PCB
+    // closing a file may wake up other tasks, so do it without the TCB locked
+    drop(fd_table);
     // do not move to its parent but under initproc
 
+    // a parent is always locked before its children, so initproc is not
+    // locked along with the current TCB
     // ++++++ access initproc TCB exclusively
-    {
+    let mut zombie_orphan = false;
+    // initproc itself may exit after all its children, and the kernel shuts down
+    if !Arc::ptr_eq(&task, &INITPROC) {
         let mut initproc_inner = INITPROC.inner_exclusive_access();
-        for child in inner.children.iter() {
-            child.inner_exclusive_access().parent = Some(Arc::downgrade(&INITPROC));
-            initproc_inner.children.push(child.clone());
+        for child in children {
+            let mut child_inner = child.inner_exclusive_access();
+            child_inner.parent = Some(Arc::downgrade(&INITPROC));
+            zombie_orphan |= child_inner.is_zombie();
+            drop(child_inner);
+            initproc_inner.children.push(child);
         }
     }
     // ++++++ release parent PCB
 
-    inner.children.clear();
-    // deallocate user space
-    inner.memory_set.recycle_data_pages();
+    // **** access current TCB exclusively
+    let mut inner = task.inner_exclusive_access();
+    // Change status to Zombie, and the parent may release it from now on
+    inner.task_status = TaskStatus::Zombie;
+    let parent = inner.parent.as_ref().and_then(|parent| parent.upgrade());
     drop(inner);
     // **** release current PCB
+    // wake up the parent, and initproc if it has got a zombie to release
+    if let Some(parent) = parent {
+        parent.child_exit.wake_all();
+    }
+    if zombie_orphan {
+        INITPROC.child_exit.wake_all();
+    }
     // drop task manually to maintain rc correctly
     drop(task);
     // we do not have to save task context
@@ -101,8 +170,8 @@ lazy_static! {
     /// the name "initproc" may be changed to any other app name like "usertests",
     /// but we have user_shell, so we don't need to change it.
     pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new(TaskControlBlock::new(
-        get_app_data_by_name("ch5b_initproc").unwrap()
-    ));
+        &read_app("ch5b_initproc").unwrap()
+    ).unwrap());
 }
 
 pub fn add_initproc() {
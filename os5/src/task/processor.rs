@@ -6,17 +6,26 @@
 
 
 use super::__switch;
-use super::{fetch_task, TaskStatus};
+use super::{fetch_task, no_task_left, switched_out_task, TaskStatus};
 use super::{TaskContext, TaskControlBlock};
-use crate::config::MAX_SYSCALL_NUM;
-use crate::mm::{VirtAddr, MapPermission, VPNRange};
+use crate::config::{MAX_HARTS, MAX_SYSCALL_NUM, USER_SPACE_END};
+use crate::errno::{SysError, SysResult};
+use crate::hart::hart_id;
+use crate::mm::{VirtAddr, MapPermission};
+use crate::sbi::shutdown;
 use crate::sync::UPSafeCell;
 use crate::timer::get_time_ms;
 use crate::trap::TrapContext;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefMut;
+use core::hint::spin_loop;
+use core::sync::atomic::Ordering;
 use lazy_static::*;
+use riscv::asm::wfi;
+use riscv::register::sstatus;
 
-/// Processor management structure
+/// Processor management structure, one for each hart
 pub struct Processor {
     /// The task currently executing on the current processor
     current: Option<Arc<TaskControlBlock>>,
@@ -41,49 +50,43 @@ impl Processor {
         self.current.as_ref().map(|task| Arc::clone(task))
     }
 
-    fn task_mmap(&self, start: usize, len: usize, port: usize) -> isize {
+    fn task_mmap(&self, start: usize, len: usize, port: usize) -> SysResult {
         let start_va = VirtAddr::from(start);
-        let end_va = VirtAddr::from(start + len);
-        if !start_va.aligned() || (port & !0x7) != 0 || (port & 0x7) == 0 {
-            return -1;
+        let end_va = VirtAddr::from(user_range_end(start, len)?);
+        if len == 0 || !start_va.aligned() || (port & !0x7) != 0 || (port & 0x7) == 0 {
+            return Err(SysError::EINVAL);
         }
         let current_task = self.current().unwrap();
         let memory_set = &mut current_task.inner_exclusive_access().memory_set;
-        let start_vpn = start_va.floor();
-        let end_vpn = end_va.ceil();
-        for vpn in VPNRange::new(start_vpn, end_vpn) {
-            if let Some(pte) = memory_set.translate(vpn) {
-                if pte.is_valid() {
-                    return -1;
-                }
-            }
+        if memory_set.overlaps(start_va.floor(), end_va.ceil()) {
+            return Err(SysError::EINVAL);
         }
         let map_perm = MapPermission::from_bits((port as u8) << 1).unwrap() | MapPermission::U;
-        memory_set.insert_framed_area(start_va, end_va, map_perm);
-        0
+        memory_set.insert_lazy_area(start_va, end_va, map_perm);
+        Ok(0)
     }
 
-    fn task_munmap(&self, start: usize, len: usize) -> isize {
+    fn task_munmap(&self, start: usize, len: usize) -> SysResult {
         let start_va = VirtAddr::from(start);
-        let end_va = VirtAddr::from(start + len);
-        if !start_va.aligned() {
-            return -1;
+        let end_va = VirtAddr::from(user_range_end(start, len)?);
+        if len == 0 || !start_va.aligned() {
+            return Err(SysError::EINVAL);
         }
         let current_task = self.current().unwrap();
         let memory_set = &mut current_task.inner_exclusive_access().memory_set;
         let start_vpn = start_va.floor();
         let end_vpn = end_va.ceil();
-        for vpn in VPNRange::new(start_vpn, end_vpn) {
-            if let Some(pte) = memory_set.translate(vpn) {
-                if !pte.is_valid() {
-                    return -1;
-                }
-            } else {
-                return -1;
-            }
+        if !memory_set.covers(start_vpn, end_vpn) {
+            return Err(SysError::EINVAL);
         }
         memory_set.unmap(start_vpn, end_vpn);
-        0
+        Ok(0)
+    }
+
+    fn task_page_fault(&self, va: usize, is_store: bool) -> bool {
+        let current_task = self.current().unwrap();
+        let memory_set = &mut current_task.inner_exclusive_access().memory_set;
+        memory_set.handle_page_fault(VirtAddr::from(va).floor(), is_store)
     }
 
     fn count_syscall(&self, syscall_id: usize) {
@@ -105,9 +108,25 @@ impl Processor {
     }
 }
 
+/// End of the user range `[start, start + len)`, which must not wrap around
+/// or leave the lower half of the address space
+fn user_range_end(start: usize, len: usize) -> Result<usize, SysError> {
+    start
+        .checked_add(len)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(SysError::EINVAL)
+}
+
 lazy_static! {
-    /// PROCESSOR instance through lazy_static!
-    pub static ref PROCESSOR: UPSafeCell<Processor> = unsafe { UPSafeCell::new(Processor::new()) };
+    /// Processor of each hart, which is only accessed by the hart itself
+    static ref PROCESSORS: Vec<UPSafeCell<Processor>> = (0..MAX_HARTS)
+        .map(|_| unsafe { UPSafeCell::new(Processor::new()) })
+        .collect();
+}
+
+/// Processor of the current hart
+fn current_processor() -> RefMut<'static, Processor> {
+    PROCESSORS[hart_id()].exclusive_access()
 }
 
 /// The main part of process execution and scheduling
@@ -116,8 +135,14 @@ lazy_static! {
 /// and switch the process through __switch
 pub fn run_tasks() {
     loop {
-        let mut processor = PROCESSOR.exclusive_access();
+        let mut processor = current_processor();
         if let Some(task) = fetch_task() {
+            // the task may have been added back while still switching out on
+            // another hart, wait until its context is saved
+            while task.on_cpu.load(Ordering::Acquire) {
+                spin_loop();
+            }
+            task.on_cpu.store(true, Ordering::Relaxed);
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             // access coming task TCB exclusively
             let mut task_inner = task.inner_exclusive_access();
@@ -128,24 +153,44 @@ pub fn run_tasks() {
             task_inner.task_status = TaskStatus::Running;
             drop(task_inner);
             // release coming task TCB manually
-            processor.current = Some(task);
+            processor.current = Some(task.clone());
             // release processor manually
             drop(processor);
             unsafe {
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
+            // back from the task, whose context has been saved, and an
+            // exited task is released here off its kernel stack
+            task.on_cpu.store(false, Ordering::Release);
+            switched_out_task();
+        } else {
+            drop(processor);
+            idle();
         }
     }
 }
 
+/// Wait for an interrupt with nothing to run, or shut down if no task is left
+fn idle() {
+    if no_task_left() {
+        println!("[kernel] No task left, shutting down.");
+        shutdown();
+    }
+    unsafe {
+        sstatus::set_sie();
+        wfi();
+        sstatus::clear_sie();
+    }
+}
+
 /// Get current task through take, leaving a None in its place
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().take_current()
+    current_processor().take_current()
 }
 
 /// Get a copy of the current task
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().current()
+    current_processor().current()
 }
 
 /// Get token of the address space of current task
@@ -165,7 +210,7 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
 
 /// Return to idle control flow for new scheduling
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
-    let mut processor = PROCESSOR.exclusive_access();
+    let mut processor = current_processor();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
     drop(processor);
     unsafe {
@@ -173,26 +218,31 @@ pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
     }
 }
 
-pub fn task_mmap(start: usize, len: usize, port: usize) -> isize {
-    PROCESSOR.exclusive_access().task_mmap(start, len, port)
+pub fn task_mmap(start: usize, len: usize, port: usize) -> SysResult {
+    current_processor().task_mmap(start, len, port)
+}
+
+pub fn task_munmap(start: usize, len: usize) -> SysResult {
+    current_processor().task_munmap(start, len)
 }
 
-pub fn task_munmap(start: usize, len: usize) -> isize {
-    PROCESSOR.exclusive_access().task_munmap(start, len)
+/// Resolve a page fault of the current task, return false if it should be killed
+pub fn task_page_fault(va: usize, is_store: bool) -> bool {
+    current_processor().task_page_fault(va, is_store)
 }
 
 pub fn count_syscall(syscall_id: usize) {
-    PROCESSOR.exclusive_access().count_syscall(syscall_id);
+    current_processor().count_syscall(syscall_id);
 }
 
 pub fn current_task_status() -> TaskStatus {
-    PROCESSOR.exclusive_access().current_task_status()
+    current_processor().current_task_status()
 }
 
 pub fn current_syscall_times() -> [u32; MAX_SYSCALL_NUM] {
-    PROCESSOR.exclusive_access().current_syscall_times()
+    current_processor().current_syscall_times()
 }
 
 pub fn current_run_time() -> usize {
-    PROCESSOR.exclusive_access().current_run_time()
+    current_processor().current_run_time()
 }
\ No newline at end of file
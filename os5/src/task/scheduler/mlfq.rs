@@ -0,0 +1,96 @@
+//! Multi-level feedback queue
+
+use super::Scheduler;
+use crate::task::{TaskControlBlock, TaskControlBlockInner};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// Number of priority levels, 0 is the highest
+const LEVELS: usize = 4;
+/// Every task is moved back to the highest level after this many time slices
+const BOOST_PERIOD: usize = 100;
+
+/// Time slices a task may use at `level` before it is moved down a level
+fn quantum(level: usize) -> usize {
+    1 << level
+}
+
+/// Tasks at a higher level always run first. A new task starts at the highest
+/// level and moves down each time it uses up its quantum, so CPU-bound tasks
+/// sink while interactive ones stay on top. All tasks are periodically boosted
+/// back to the highest level to avoid starvation.
+///
+/// Tasks running on other harts or blocked are not in any queue when a boost
+/// happens, so each boost starts a new epoch, and a task from an earlier epoch
+/// is boosted the next time it is enqueued or ticked.
+pub struct MlfqScheduler {
+    ready_queues: [VecDeque<Arc<TaskControlBlock>>; LEVELS],
+    /// Time slices since the last boost
+    ticks: usize,
+    /// Number of boosts so far
+    epoch: usize,
+}
+
+impl MlfqScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queues: Default::default(),
+            ticks: 0,
+            epoch: 0,
+        }
+    }
+    fn boost(&mut self) {
+        self.epoch = self.epoch.wrapping_add(1);
+        for level in 1..LEVELS {
+            let tasks = core::mem::take(&mut self.ready_queues[level]);
+            self.ready_queues[0].extend(tasks);
+        }
+    }
+    /// Apply the boosts `inner` has missed
+    fn catch_up(&self, inner: &mut TaskControlBlockInner) {
+        if inner.sched_epoch != self.epoch {
+            inner.sched_epoch = self.epoch;
+            inner.sched_level = 0;
+            inner.sched_ticks = 0;
+        }
+    }
+}
+
+impl Scheduler for MlfqScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut inner = task.inner_exclusive_access();
+        self.catch_up(&mut inner);
+        let level = inner.sched_level;
+        drop(inner);
+        self.ready_queues[level].push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queues
+            .iter_mut()
+            .find_map(|ready_queue| ready_queue.pop_front())
+    }
+    fn is_empty(&self) -> bool {
+        self.ready_queues.iter().all(|ready_queue| ready_queue.is_empty())
+    }
+    fn tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        self.ticks += 1;
+        if self.ticks >= BOOST_PERIOD {
+            self.ticks = 0;
+            self.boost();
+            return true;
+        }
+        let mut inner = task.inner_exclusive_access();
+        self.catch_up(&mut inner);
+        inner.sched_ticks += 1;
+        if inner.sched_ticks < quantum(inner.sched_level) {
+            return false;
+        }
+        inner.sched_ticks = 0;
+        inner.sched_level = (inner.sched_level + 1).min(LEVELS - 1);
+        true
+    }
+    fn on_block(&mut self, task: &Arc<TaskControlBlock>) {
+        // giving up the CPU early keeps the task at its level with a fresh quantum
+        task.inner_exclusive_access().sched_ticks = 0;
+    }
+}
@@ -0,0 +1,49 @@
+//! Scheduling policies of [`super::TaskManager`]
+//!
+//! A policy is chosen at build time with a cargo feature: `sched-fifo`,
+//! `sched-rr` or `sched-mlfq`, and stride scheduling is used if none is set.
+
+mod fifo;
+mod mlfq;
+mod rr;
+mod stride;
+
+use super::TaskControlBlock;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+pub use fifo::FifoScheduler;
+pub use mlfq::MlfqScheduler;
+pub use rr::RoundRobinScheduler;
+pub use stride::StrideScheduler;
+
+/// A scheduling policy, which owns the ready tasks
+pub trait Scheduler: Send {
+    /// Add a ready task
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    /// Take the next task to run
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>>;
+    /// Whether no task is ready
+    fn is_empty(&self) -> bool;
+    /// The time slice of the running `task` is over, return whether it should be preempted
+    fn tick(&mut self, _task: &Arc<TaskControlBlock>) -> bool {
+        true
+    }
+    /// The running `task` gives up the CPU to wait for an event
+    fn on_block(&mut self, _task: &Arc<TaskControlBlock>) {}
+    /// `task` is woken up, and is added right after
+    fn on_wake(&mut self, _task: &Arc<TaskControlBlock>) {}
+}
+
+/// The scheduling policy chosen at build time
+pub fn new_scheduler() -> Box<dyn Scheduler> {
+    if cfg!(feature = "sched-fifo") {
+        Box::new(FifoScheduler::new())
+    } else if cfg!(feature = "sched-rr") {
+        Box::new(RoundRobinScheduler::new())
+    } else if cfg!(feature = "sched-mlfq") {
+        Box::new(MlfqScheduler::new())
+    } else {
+        Box::new(StrideScheduler::new())
+    }
+}
@@ -0,0 +1,100 @@
+//! Stride scheduling
+
+use super::Scheduler;
+use crate::config::BIG_STRIDE;
+use crate::task::TaskControlBlock;
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
+use core::cmp::Ordering;
+
+/// Whether pass `a` comes before pass `b`
+///
+/// Passes wrap around, but priorities are at least 2, so ready tasks never
+/// drift more than `BIG_STRIDE / 2` apart, and the wrapping difference of two
+/// passes tells which one is behind.
+fn pass_before(a: usize, b: usize) -> bool {
+    (a.wrapping_sub(b) as isize) < 0
+}
+
+/// A ready task with its pass when it was queued
+struct Entry {
+    pass: usize,
+    /// Order of arrival, which breaks ties between equal passes
+    seq: usize,
+    task: Arc<TaskControlBlock>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    /// The smallest pass, then the earliest arrival, is the greatest, at the top of the heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        if pass_before(self.pass, other.pass) {
+            Ordering::Greater
+        } else if pass_before(other.pass, self.pass) {
+            Ordering::Less
+        } else {
+            other.seq.cmp(&self.seq)
+        }
+    }
+}
+
+/// The task with the smallest pass runs next, and its pass grows by
+/// `BIG_STRIDE / priority`, so each task gets CPU time in proportion to its
+/// priority
+pub struct StrideScheduler {
+    ready_queue: BinaryHeap<Entry>,
+    /// Pass of the last fetched task, no ready task is behind it
+    min_pass: usize,
+    next_seq: usize,
+}
+
+impl StrideScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: BinaryHeap::new(),
+            min_pass: 0,
+            next_seq: 0,
+        }
+    }
+}
+
+impl Scheduler for StrideScheduler {
+    /// A new task, or one which has been blocked for long, joins at the
+    /// minimum pass instead of running alone until it catches up
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut inner = task.inner_exclusive_access();
+        let pass = match inner.pass {
+            Some(pass) if pass.wrapping_sub(self.min_pass) <= BIG_STRIDE / 2 => pass,
+            _ => self.min_pass,
+        };
+        inner.pass = Some(pass);
+        drop(inner);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.ready_queue.push(Entry { pass, seq, task });
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let Entry { pass, task, .. } = self.ready_queue.pop()?;
+        self.min_pass = pass;
+        let mut inner = task.inner_exclusive_access();
+        inner.pass = Some(pass.wrapping_add(BIG_STRIDE / inner.priority));
+        drop(inner);
+        Some(task)
+    }
+    fn is_empty(&self) -> bool {
+        self.ready_queue.is_empty()
+    }
+}
@@ -1,15 +1,19 @@
 //! Types related to task management & Functions for completely changing TCB
 
-use super::TaskContext;
+use super::{TaskContext, WaitQueue};
 use super::{pid_alloc, KernelStack, PidHandle};
-use crate::config::{TRAP_CONTEXT, MAX_SYSCALL_NUM};
+use crate::config::{TRAP_CONTEXT, MAX_SYSCALL_NUM, MAX_FDS};
+use crate::errno::{SysError, SysResult};
+use crate::fs::{File, Stdin, Stdout};
 use crate::mm::{MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
-use crate::sync::UPSafeCell;
+use crate::sync::{SpinLock, SpinLockGuard};
 use crate::trap::{trap_handler, TrapContext};
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::{Arc, Weak};
+use alloc::vec;
 use alloc::vec::Vec;
-use core::cell::RefMut;
+use core::sync::atomic::AtomicBool;
 
 /// Task control block structure
 ///
@@ -20,14 +24,19 @@ pub struct TaskControlBlock {
     pub pid: PidHandle,
     /// Kernel stack corresponding to PID
     pub kernel_stack: KernelStack,
+    /// The process waits here for its children to exit
+    pub child_exit: WaitQueue,
+    /// Set while a hart runs the process, until its context is saved after
+    /// switching out, so that no other hart switches to it before that
+    pub on_cpu: AtomicBool,
     // mutable
-    inner: UPSafeCell<TaskControlBlockInner>,
+    inner: SpinLock<TaskControlBlockInner>,
 }
 
 /// Structure containing more process content
 ///
 /// Store the contents that will change during operation
-/// and are wrapped by UPSafeCell to provide mutual exclusion
+/// and are wrapped by SpinLock to provide mutual exclusion
 pub struct TaskControlBlockInner {
     /// The physical page number of the frame where the trap context is placed
     pub trap_cx_ppn: PhysPageNum,
@@ -48,13 +57,23 @@ pub struct TaskControlBlockInner {
     /// It is set when active exit or execution error occurs
     pub exit_code: i32,
 
-    pub pass: usize,
+    /// Pass under stride scheduling, `None` until the task first gets ready
+    pub pass: Option<usize>,
 
     pub priority: usize,
+    /// Queue level under the MLFQ policy, 0 is the highest
+    pub sched_level: usize,
+    /// Time slices used at the current MLFQ level
+    pub sched_ticks: usize,
+    /// The last MLFQ boost applied to `sched_level`
+    pub sched_epoch: usize,
 
     pub syscall_times: Box<[u32; MAX_SYSCALL_NUM]>,
 
     pub start_time: Option<usize>,
+
+    /// Open files indexed by file descriptor, `None` for a closed descriptor
+    pub fd_table: Vec<Option<Arc<dyn File>>>,
 }
 
 /// Simple access to its internal fields
@@ -76,48 +95,75 @@ impl TaskControlBlockInner {
     pub fn is_zombie(&self) -> bool {
         self.get_status() == TaskStatus::Zombie
     }
+    /// The lowest closed file descriptor, the table grows if all are open,
+    /// up to [`MAX_FDS`] descriptors
+    pub fn alloc_fd(&mut self) -> SysResult {
+        if let Some(fd) = self.fd_table.iter().position(|file| file.is_none()) {
+            Ok(fd)
+        } else if self.fd_table.len() < MAX_FDS {
+            self.fd_table.push(None);
+            Ok(self.fd_table.len() - 1)
+        } else {
+            Err(SysError::EMFILE)
+        }
+    }
+    /// The open file of descriptor `fd`
+    pub fn get_file(&self, fd: usize) -> Option<Arc<dyn File>> {
+        self.fd_table.get(fd)?.clone()
+    }
 }
 
 impl TaskControlBlock {
-    /// Get the mutex to get the RefMut TaskControlBlockInner
-    pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
+    /// Lock the mutex to get the TaskControlBlockInner
+    pub fn inner_exclusive_access(&self) -> SpinLockGuard<'_, TaskControlBlockInner> {
         self.inner.exclusive_access()
     }
 
     /// Create a new process
     ///
     /// At present, it is only used for the creation of initproc
-    pub fn new(elf_data: &[u8]) -> Self {
+    pub fn new(elf_data: &[u8]) -> Result<Self, SysError> {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data)?;
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
         // alloc a pid and a kernel stack in kernel space
         let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack = KernelStack::new(&pid_handle)?;
         let kernel_stack_top = kernel_stack.get_top();
         // push a task context which goes to trap_return to the top of kernel stack
         let task_control_block = Self {
             pid: pid_handle,
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: user_sp,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    memory_set,
-                    parent: None,
-                    children: Vec::new(),
-                    exit_code: 0,
-                    pass: 0,
-                    priority: 16,
-                    syscall_times: Box::new([0; MAX_SYSCALL_NUM]),
-                    start_time: None,
-                })
-            },
+            child_exit: WaitQueue::new(),
+            on_cpu: AtomicBool::new(false),
+            inner: SpinLock::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                base_size: user_sp,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                memory_set,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                pass: None,
+                priority: 16,
+                sched_level: 0,
+                sched_ticks: 0,
+                sched_epoch: 0,
+                syscall_times: Box::new([0; MAX_SYSCALL_NUM]),
+                start_time: None,
+                fd_table: vec![
+                    // 0 -> stdin
+                    Some(Arc::new(Stdin)),
+                    // 1 -> stdout
+                    Some(Arc::new(Stdout)),
+                    // 2 -> stderr
+                    Some(Arc::new(Stdout)),
+                ],
+            }),
         };
         // prepare TrapContext in user space
         let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
@@ -128,12 +174,16 @@ impl TaskControlBlock {
             kernel_stack_top,
             trap_handler as usize,
         );
-        task_control_block
+        Ok(task_control_block)
     }
     /// Load a new elf to replace the original application address space and start execution
-    pub fn exec(&self, elf_data: &[u8]) {
+    /// with `args` as its argv
+    ///
+    /// The original address space is kept if the new one can not be built.
+    pub fn exec(&self, elf_data: &[u8], args: &[String]) -> Result<(), SysError> {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let (mut memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data)?;
+        let (user_sp, argv_base) = memory_set.push_args(user_sp, args)?;
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
@@ -154,41 +204,48 @@ impl TaskControlBlock {
             self.kernel_stack.get_top(),
             trap_handler as usize,
         );
+        trap_cx.x[10] = args.len();
+        trap_cx.x[11] = argv_base;
+        Ok(())
         // **** release inner automatically
     }
     /// Fork from parent to child
-    pub fn fork(self: &Arc<TaskControlBlock>) -> Arc<TaskControlBlock> {
+    pub fn fork(self: &Arc<TaskControlBlock>) -> Result<Arc<TaskControlBlock>, SysError> {
         // ---- access parent PCB exclusively
         let mut parent_inner = self.inner_exclusive_access();
-        // copy user space(include trap context)
-        let memory_set = MemorySet::from_existed_user(&parent_inner.memory_set);
+        // share user space copy-on-write (trap context is copied)
+        let memory_set = MemorySet::from_existed_user(&mut parent_inner.memory_set)?;
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
         // alloc a pid and a kernel stack in kernel space
         let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack = KernelStack::new(&pid_handle)?;
         let kernel_stack_top = kernel_stack.get_top();
         let task_control_block = Arc::new(TaskControlBlock {
             pid: pid_handle,
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: parent_inner.base_size,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    memory_set,
-                    parent: Some(Arc::downgrade(self)),
-                    children: Vec::new(),
-                    exit_code: 0,
-                    pass: parent_inner.pass,
-                    priority: parent_inner.priority,
-                    syscall_times: parent_inner.syscall_times.clone(),
-                    start_time: parent_inner.start_time,
-                })
-            },
+            child_exit: WaitQueue::new(),
+            on_cpu: AtomicBool::new(false),
+            inner: SpinLock::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                base_size: parent_inner.base_size,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                memory_set,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                pass: None,
+                priority: parent_inner.priority,
+                sched_level: 0,
+                sched_ticks: 0,
+                sched_epoch: 0,
+                syscall_times: parent_inner.syscall_times.clone(),
+                start_time: parent_inner.start_time,
+                fd_table: parent_inner.fd_table.clone(),
+            }),
         });
         // add child
         parent_inner.children.push(task_control_block.clone());
@@ -197,13 +254,18 @@ impl TaskControlBlock {
         let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
         trap_cx.kernel_sp = kernel_stack_top;
         // return
-        task_control_block
+        Ok(task_control_block)
         // ---- release parent PCB automatically
         // **** release children PCB automatically
     }
 
-    pub fn spawn(self: &Arc<TaskControlBlock>, elf_data: &[u8]) -> Arc<TaskControlBlock> {
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+    pub fn spawn(
+        self: &Arc<TaskControlBlock>,
+        elf_data: &[u8],
+        args: &[String],
+    ) -> Result<Arc<TaskControlBlock>, SysError> {
+        let (mut memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data)?;
+        let (user_sp, argv_base) = memory_set.push_args(user_sp, args)?;
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
@@ -211,28 +273,32 @@ impl TaskControlBlock {
         // alloc a pid and a kernel stack in kernel space
         let mut parent_inner = self.inner_exclusive_access();
         let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack = KernelStack::new(&pid_handle)?;
         let kernel_stack_top = kernel_stack.get_top();
         // push a task context which goes to trap_return to the top of kernel stack
         let task_control_block = Arc::new(TaskControlBlock {
             pid: pid_handle,
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: parent_inner.base_size,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    memory_set,
-                    parent: Some(Arc::downgrade(self)),
-                    children: Vec::new(),
-                    exit_code: 0,
-                    pass: 0,
-                    priority: parent_inner.priority,
-                    syscall_times: Box::new([0; MAX_SYSCALL_NUM]),
-                    start_time: None,
-                })
-            },
+            child_exit: WaitQueue::new(),
+            on_cpu: AtomicBool::new(false),
+            inner: SpinLock::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                base_size: parent_inner.base_size,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                memory_set,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                pass: None,
+                priority: parent_inner.priority,
+                sched_level: 0,
+                sched_ticks: 0,
+                sched_epoch: 0,
+                syscall_times: Box::new([0; MAX_SYSCALL_NUM]),
+                start_time: None,
+                fd_table: parent_inner.fd_table.clone(),
+            }),
         });
         parent_inner.children.push(task_control_block.clone());
         // prepare TrapContext in user space
@@ -244,7 +310,9 @@ impl TaskControlBlock {
             kernel_stack_top,
             trap_handler as usize,
         );
-        task_control_block
+        trap_cx.x[10] = args.len();
+        trap_cx.x[11] = argv_base;
+        Ok(task_control_block)
     }
 
     pub fn getpid(&self) -> usize {
@@ -253,11 +321,12 @@ impl TaskControlBlock {
 }
 
 #[derive(Copy, Clone, PartialEq)]
-/// task status: UnInit, Ready, Running, Exited
+/// task status: UnInit, Ready, Running, Exited, Blocked
 pub enum TaskStatus {
     #[allow(unused)]
     UnInit,
     Ready,
     Running,
     Zombie,
+    Blocked,
 }
@@ -0,0 +1,42 @@
+//! Implementation of [`WaitQueue`]
+//!
+//! Tasks waiting for an event are blocked on a wait queue, and are put back
+//! to the ready queue when the event happens.
+
+use super::{block_current_and_run_next, wakeup_task, TaskControlBlock};
+use crate::sync::SpinLock;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// A queue of blocked tasks waiting for the same event
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<Arc<TaskControlBlock>>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+    /// Block the current task on the queue and switch to the next task,
+    /// unless `ready` returns true
+    ///
+    /// `ready` is checked with the queue locked, so the event can not slip in
+    /// between the check and blocking, as long as it happens before the
+    /// waiters are woken up.
+    pub fn wait_until(&self, ready: impl FnOnce() -> bool) {
+        let mut waiters = self.waiters.exclusive_access();
+        if ready() {
+            return;
+        }
+        block_current_and_run_next(move |task| waiters.push_back(task));
+    }
+    /// Wake up all the waiting tasks
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.exclusive_access());
+        for task in waiters {
+            wakeup_task(task);
+        }
+    }
+}
@@ -1,9 +1,26 @@
-use crate::config::CLOCK_FREQ;
+//! RISC-V timer-related functionality
+//!
+//! The timer interrupt serves two purposes: it ends the time slice of the
+//! running task, and it wakes up tasks sleeping until a deadline. Sleeping
+//! tasks are kept in a queue sorted by deadline, and the timer of each hart
+//! is always programmed for whichever comes first.
+
+use crate::config::{CLOCK_FREQ, MAX_HARTS};
+use crate::hart::hart_id;
 use crate::sbi::set_timer;
+use crate::sync::SpinLock;
+use crate::task::{wakeup_task, TaskControlBlock};
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use lazy_static::*;
 use riscv::register::time;
 
 const TICKS_PER_SEC: usize = 100;
+const MILLI_PER_SEC: usize = 1_000;
 const MICRO_PER_SEC: usize = 1_000_000;
+const NANO_PER_SEC: usize = 1_000_000_000;
 
 pub fn get_time() -> usize {
     time::read()
@@ -13,10 +30,109 @@ pub fn get_time_us() -> usize {
     time::read() / (CLOCK_FREQ / MICRO_PER_SEC)
 }
 
+pub fn get_time_ms() -> usize {
+    get_time_us() / 1000
+}
+
+/// Convert a duration to clock ticks, saturating on overflow
+pub fn duration_to_ticks(sec: usize, nsec: usize) -> usize {
+    sec.saturating_mul(CLOCK_FREQ)
+        .saturating_add(nsec / (NANO_PER_SEC / CLOCK_FREQ))
+}
+
+/// Convert milliseconds to clock ticks, saturating on overflow
+pub fn ms_to_ticks(ms: usize) -> usize {
+    ms.saturating_mul(CLOCK_FREQ / MILLI_PER_SEC)
+}
+
+/// A task sleeping until `deadline`
+struct Sleeper {
+    deadline: usize,
+    task: Arc<TaskControlBlock>,
+}
+
+impl PartialEq for Sleeper {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Sleeper {}
+
+impl PartialOrd for Sleeper {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sleeper {
+    /// The earliest deadline is the greatest, at the top of the heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// Sleeping tasks and the end of the current time slice of each hart
+struct Timer {
+    sleepers: BinaryHeap<Sleeper>,
+    slice_end: [usize; MAX_HARTS],
+}
+
+impl Timer {
+    /// Program the timer of the current hart for the end of its time slice
+    /// or the nearest deadline
+    fn program(&self) {
+        let slice_end = self.slice_end[hart_id()];
+        let next = match self.sleepers.peek() {
+            Some(sleeper) => sleeper.deadline.min(slice_end),
+            None => slice_end,
+        };
+        set_timer(next);
+    }
+}
+
+lazy_static! {
+    static ref TIMER: SpinLock<Timer> = SpinLock::new(Timer {
+        sleepers: BinaryHeap::new(),
+        slice_end: [0; MAX_HARTS],
+    });
+}
+
+/// Start a new time slice on the current hart
 pub fn set_next_trigger() {
-    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
+    let mut timer = TIMER.exclusive_access();
+    timer.slice_end[hart_id()] = get_time() + CLOCK_FREQ / TICKS_PER_SEC;
+    timer.program();
 }
 
-pub fn get_time_ms() -> usize {
-    get_time_us() / 1000
-}
\ No newline at end of file
+/// Put `task` to sleep until `deadline` in clock ticks, it must have been
+/// marked blocked and is woken up by [`check_timer`]
+pub fn add_sleeper(deadline: usize, task: Arc<TaskControlBlock>) {
+    let mut timer = TIMER.exclusive_access();
+    timer.sleepers.push(Sleeper { deadline, task });
+    timer.program();
+}
+
+/// Wake up the sleepers whose deadline has passed on a timer interrupt,
+/// return whether the time slice of the current hart is over
+pub fn check_timer() -> bool {
+    let now = get_time();
+    let mut timer = TIMER.exclusive_access();
+    let mut expired = Vec::new();
+    while timer
+        .sleepers
+        .peek()
+        .map_or(false, |sleeper| sleeper.deadline <= now)
+    {
+        expired.push(timer.sleepers.pop().unwrap());
+    }
+    let slice_over = now >= timer.slice_end[hart_id()];
+    if !slice_over {
+        timer.program();
+    }
+    drop(timer);
+    for sleeper in expired {
+        wakeup_task(sleeper.task);
+    }
+    slice_over
+}
@@ -15,11 +15,13 @@
 mod context;
 
 use crate::config::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::hart::hart_id;
 use crate::syscall::syscall;
 use crate::task::{
-    current_trap_cx, current_user_token, exit_current_and_run_next, suspend_current_and_run_next,
+    current_trap_cx, current_user_token, exit_current_and_run_next, task_page_fault,
+    tick_current_and_run_next,
 };
-use crate::timer::set_next_trigger;
+use crate::timer::{check_timer, set_next_trigger};
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt, Trap},
@@ -33,8 +35,11 @@ pub fn init() {
 }
 
 fn set_kernel_trap_entry() {
+    extern "C" {
+        fn __kernel_trap();
+    }
     unsafe {
-        stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+        stvec::write(__kernel_trap as usize, TrapMode::Direct);
     }
 }
 
@@ -66,6 +71,12 @@ pub fn trap_handler() -> ! {
             cx = current_trap_cx();
             cx.x[10] = result as usize;
         }
+        Trap::Exception(Exception::LoadPageFault) if task_page_fault(stval, false) => {
+            // lazy page has been allocated, retry the load
+        }
+        Trap::Exception(Exception::StorePageFault) if task_page_fault(stval, true) => {
+            // lazy or copy-on-write page is ready, retry the store
+        }
         Trap::Exception(Exception::StoreFault)
         | Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionFault)
@@ -86,9 +97,21 @@ pub fn trap_handler() -> ! {
             // illegal instruction exit code
             exit_current_and_run_next(-3);
         }
+        Trap::Exception(_) => {
+            println!(
+                "[kernel] {:?} in application, bad instruction = {:#x}, core dumped.",
+                scause.cause(),
+                current_trap_cx().sepc,
+            );
+            // other exceptions are caused by the application as well
+            exit_current_and_run_next(-3);
+        }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            set_next_trigger();
-            suspend_current_and_run_next();
+            // wake up sleepers, and switch task only if the time slice is over
+            if check_timer() {
+                set_next_trigger();
+                tick_current_and_run_next();
+            }
         }
         _ => {
             panic!(
@@ -104,6 +127,8 @@ pub fn trap_handler() -> ! {
 #[no_mangle]
 pub fn trap_return() -> ! {
     set_user_trap_entry();
+    // the task may run on another hart than the one it trapped on last time
+    current_trap_cx().hart_id = hart_id();
     let trap_cx_ptr = TRAP_CONTEXT;
     let user_satp = current_user_token();
     extern "C" {
@@ -124,8 +149,22 @@ pub fn trap_return() -> ! {
 }
 
 #[no_mangle]
-pub fn trap_from_kernel() -> ! {
-    panic!("a trap {:?} from kernel!", scause::read().cause());
+/// Handle a trap taken in the kernel, `__kernel_trap` returns to the interrupted code
+///
+/// Interrupts are only enabled while the idle control flow waits for the timer.
+pub fn trap_from_kernel() {
+    match scause::read().cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            if check_timer() {
+                set_next_trigger();
+            }
+        }
+        cause => panic!(
+            "a trap {:?} from kernel, stval = {:#x}!",
+            cause,
+            stval::read()
+        ),
+    }
 }
 
 pub use context::TrapContext;
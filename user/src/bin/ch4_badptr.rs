@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    mmap, munmap, syscall, TimeVal, EFAULT, ENAMETOOLONG, SYSCALL_EXEC, SYSCALL_GETTIMEOFDAY,
+    SYSCALL_SPAWN, SYSCALL_TASK_INFO, SYSCALL_WRITE,
+};
+
+/*
+理想结果：输出 Test 04_8 badptr OK!
+*/
+
+#[no_mangle]
+fn main() -> i32 {
+    let start: usize = 0x10000000;
+    let len: usize = 4096;
+    // unmapped or kernel addresses
+    assert_eq!(syscall(SYSCALL_GETTIMEOFDAY, [0, 0, 0]), -EFAULT);
+    assert_eq!(syscall(SYSCALL_WRITE, [1, start, 16]), -EFAULT);
+    assert_eq!(syscall(SYSCALL_EXEC, [usize::MAX - len + 1, 0, 0]), -EFAULT);
+    assert_eq!(syscall(SYSCALL_TASK_INFO, [usize::MAX - len * 2 + 1, 0, 0]), -EFAULT);
+    // a read-only page can not be written by the kernel either
+    assert_eq!(mmap(start, len, 1), 0);
+    assert_eq!(syscall(SYSCALL_GETTIMEOFDAY, [start, 0, 0]), -EFAULT);
+    assert_eq!(munmap(start, len), 0);
+    // a value straddling two pages
+    assert_eq!(mmap(start, len * 2, 3), 0);
+    let ts = start + len - 8;
+    assert_eq!(syscall(SYSCALL_GETTIMEOFDAY, [ts, 0, 0]), 0);
+    let ts = unsafe { &*(ts as *const TimeVal) };
+    assert!(ts.sec > 0 || ts.usec > 0);
+    // a string without terminator
+    for addr in start..(start + len * 2) {
+        unsafe {
+            *(addr as *mut u8) = b'a';
+        }
+    }
+    assert_eq!(syscall(SYSCALL_SPAWN, [start, 0, 0]), -ENAMETOOLONG);
+    assert_eq!(munmap(start, len * 2), 0);
+    println!("Test 04_8 badptr OK!");
+    0
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{mmap, munmap};
+
+/*
+理想结果：输出 Test 04_7 ummap3 OK!
+*/
+
+#[no_mangle]
+fn main() -> i32 {
+    let start: usize = 0x10000000;
+    let len: usize = 4096;
+    let prot: usize = 3;
+    // unmap the middle page of an area
+    assert_eq!(0, mmap(start, len * 3, prot));
+    for i in start..(start + len * 3) {
+        let addr: *mut u8 = i as *mut u8;
+        unsafe {
+            *addr = i as u8;
+        }
+    }
+    assert_eq!(munmap(start + len, len), 0);
+    assert_eq!(munmap(start + len, len), -1);
+    assert_eq!(munmap(start, len * 3), -1);
+    for i in (start..(start + len)).chain((start + len * 2)..(start + len * 3)) {
+        let addr: *mut u8 = i as *mut u8;
+        unsafe {
+            assert_eq!(*addr, i as u8);
+        }
+    }
+    // fill the hole again, the new page must be zeroed
+    assert_eq!(mmap(start + len, len, prot), 0);
+    for i in (start + len)..(start + len * 2) {
+        let addr: *mut u8 = i as *mut u8;
+        unsafe {
+            assert_eq!(*addr, 0);
+        }
+    }
+    assert_eq!(munmap(start, len * 3), 0);
+    // an empty range is rejected, and leaves no area behind
+    assert_eq!(mmap(start, 0, prot), -1);
+    assert_eq!(munmap(start, 0), -1);
+    assert_eq!(munmap(start, len), -1);
+    // touch more pages in total than there are physical frames
+    let big_len = len * 256;
+    for round in 0..200 {
+        assert_eq!(mmap(start, big_len, prot), 0);
+        for page in (start..(start + big_len)).step_by(len) {
+            let addr: *mut usize = page as *mut usize;
+            unsafe {
+                *addr = round;
+            }
+        }
+        for page in (start..(start + big_len)).step_by(len) {
+            let addr: *mut usize = page as *mut usize;
+            unsafe {
+                assert_eq!(*addr, round);
+            }
+        }
+        assert_eq!(munmap(start, big_len), 0);
+    }
+    println!("Test 04_7 ummap3 OK!");
+    0
+}
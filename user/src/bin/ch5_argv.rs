@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+#[macro_use]
+extern crate user_lib;
+
+use alloc::string::String;
+use user_lib::{exec, fork, spawn_args, waitpid, E2BIG, WEXITSTATUS};
+
+/// 程序行为：带参数 spawn 和 exec 自身，子进程检查收到的 argv 并以 argc 作为返回值；
+/// 参数总长度超过限制时 spawn 返回 E2BIG。
+
+/// 理想输出：
+/// Test argv OK!
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc > 0 {
+        assert_eq!(argv[0], "ch5_argv");
+        if argc == 3 {
+            assert_eq!(argv[1], "hello");
+            assert_eq!(argv[2], "world");
+        } else {
+            assert_eq!(argc, 2);
+            assert_eq!(argv[1], "exec");
+        }
+        return argc as i32;
+    }
+    let mut exit_code: i32 = 0;
+    let args = ["ch5_argv\0", "hello\0", "world\0"].map(|arg| arg.as_ptr());
+    let cpid = spawn_args("ch5_argv\0", &[args[0], args[1], args[2], core::ptr::null()]);
+    assert!(cpid > 0, "spawn failed");
+    assert_eq!(waitpid(cpid as usize, &mut exit_code), cpid);
+    assert_eq!(WEXITSTATUS(exit_code), 3);
+    let cpid = fork();
+    if cpid == 0 {
+        let args = ["ch5_argv\0".as_ptr(), "exec\0".as_ptr(), core::ptr::null()];
+        exec("ch5_argv\0", &args);
+        panic!("exec failed");
+    }
+    assert_eq!(waitpid(cpid as usize, &mut exit_code), cpid);
+    assert_eq!(WEXITSTATUS(exit_code), 2);
+    let mut long_arg = String::new();
+    for _ in 0..3000 {
+        long_arg.push('a');
+    }
+    long_arg.push('\0');
+    let args = [long_arg.as_ptr(), long_arg.as_ptr(), core::ptr::null()];
+    assert_eq!(spawn_args("ch5_argv\0", &args), -E2BIG);
+    println!("Test argv OK!");
+    0
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, exec, exit, fork, open, read, spawn, unlink, wait, write, OpenFlags, ENOEXEC,
+};
+
+/// 程序行为：对文本文件和只含 ELF 头的截断文件分别 spawn 和 exec，均应返回 ENOEXEC，且调用者不受影响。
+
+/// 理想输出：
+/// Test exec bad elf OK!
+
+fn create(path: &str, data: &[u8]) {
+    let fd = open(
+        path,
+        OpenFlags::CREATE | OpenFlags::WRONLY | OpenFlags::TRUNC,
+    );
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, data), data.len() as isize);
+    close(fd as usize);
+}
+
+fn check(path: &str) {
+    assert_eq!(spawn(path), -ENOEXEC);
+    let pid = fork();
+    if pid == 0 {
+        let ret = exec(path, &[core::ptr::null()]);
+        exit(if ret == -ENOEXEC { 0 } else { 1 });
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(wait(&mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    create("ch5_exec_text\0", b"this is not an elf\n");
+    check("ch5_exec_text\0");
+
+    // the program headers are cut off
+    let mut header = [0u8; 64];
+    let fd = open("ch5_getpid\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    assert_eq!(read(fd as usize, &mut header), header.len() as isize);
+    close(fd as usize);
+    create("ch5_exec_cut\0", &header);
+    check("ch5_exec_cut\0");
+
+    assert_eq!(unlink("ch5_exec_text\0"), 0);
+    assert_eq!(unlink("ch5_exec_cut\0"), 0);
+    println!("Test exec bad elf OK!");
+    0
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, dup, exit, fork, fstat, wait, write, Stat, StatMode, EBADF, STDOUT};
+
+/// 程序行为：复制 stdout 的文件描述符并通过新描述符输出，检查 fstat 的结果；关闭后再使用应返回 EBADF。
+/// 子进程继承父进程的文件描述符表，关闭子进程中的描述符不影响父进程。
+
+/// 理想输出：
+/// Hello from a duplicated stdout!
+/// Hello from the child!
+/// Test file descriptors OK!
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = dup(STDOUT);
+    assert!(fd > 2);
+    let fd = fd as usize;
+    assert_eq!(dup(fd + 100), -EBADF);
+    let stat = Stat::new();
+    assert_eq!(fstat(fd, &stat), 0);
+    assert_eq!(stat.mode, StatMode::CHR);
+    let msg = b"Hello from a duplicated stdout!\n";
+    assert_eq!(write(fd, msg), msg.len() as isize);
+    let pid = fork();
+    if pid == 0 {
+        let msg = b"Hello from the child!\n";
+        assert_eq!(write(fd, msg), msg.len() as isize);
+        assert_eq!(close(fd), 0);
+        exit(0);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(wait(&mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    // the child closed its own copy
+    assert_eq!(write(fd, b""), 0);
+    assert_eq!(close(fd), 0);
+    assert_eq!(close(fd), -EBADF);
+    assert_eq!(write(fd, b"x"), -EBADF);
+    // the lowest free descriptor is reused
+    assert_eq!(dup(STDOUT), fd as isize);
+    assert_eq!(close(fd), 0);
+    println!("Test file descriptors OK!");
+    0
+}
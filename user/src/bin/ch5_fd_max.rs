@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, dup, exit, fork, wait, EMFILE, STDOUT};
+
+/// 程序行为：不断复制 stdout 直到文件描述符表填满，此后 dup 应返回 EMFILE 而内核不崩溃；
+/// 关闭一个描述符后又可以复制，全部关闭后从最小的描述符重新分配。子进程继承填满的表。
+
+/// 理想输出：
+/// Test fd limit OK!
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let first = dup(STDOUT);
+    assert!(first > 2);
+    let mut last = first;
+    loop {
+        let fd = dup(STDOUT);
+        if fd < 0 {
+            assert_eq!(fd, -EMFILE);
+            break;
+        }
+        assert_eq!(fd, last + 1);
+        last = fd;
+    }
+    assert_eq!(dup(STDOUT), -EMFILE);
+    // a closed descriptor can be taken again
+    assert_eq!(close(first as usize), 0);
+    assert_eq!(dup(STDOUT), first);
+    // the child inherits the full table
+    let pid = fork();
+    if pid == 0 {
+        assert_eq!(dup(STDOUT), -EMFILE);
+        exit(0);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(wait(&mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    for fd in first..=last {
+        assert_eq!(close(fd as usize), 0);
+    }
+    assert_eq!(dup(STDOUT), first);
+    assert_eq!(close(first as usize), 0);
+    println!("Test fd limit OK!");
+    0
+}
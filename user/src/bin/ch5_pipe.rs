@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, dup, exit, fork, fstat, pipe, read, wait, write, Stat, StatMode, EMFILE, EPIPE, STDOUT,
+};
+
+/// 程序行为：子进程通过管道向父进程写入远超管道缓冲区大小的数据，写端在缓冲区满时阻塞，读端在无数据时阻塞。
+/// 所有写端关闭后读端读到文件末尾（返回 0）；所有读端关闭后写入返回 EPIPE。
+/// 文件描述符表只剩一个空位时创建管道返回 EMFILE，且不占用该空位。
+
+/// 理想输出：
+/// Test pipe OK!
+
+const LENGTH: usize = 10000;
+
+fn byte_at(i: usize) -> u8 {
+    (i * 7 % 251) as u8
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut pipe_fd = [0usize; 2];
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    let [read_fd, write_fd] = pipe_fd;
+    let stat = Stat::new();
+    assert_eq!(fstat(read_fd, &stat), 0);
+    assert_eq!(stat.mode, StatMode::FIFO);
+    // the wrong end
+    assert!(write(read_fd, b"x") < 0);
+    assert!(read(write_fd, &mut [0u8; 1]) < 0);
+
+    let pid = fork();
+    if pid == 0 {
+        close(read_fd);
+        let mut data = [0u8; LENGTH];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = byte_at(i);
+        }
+        assert_eq!(write(write_fd, &data), LENGTH as isize);
+        close(write_fd);
+        exit(0);
+    }
+    // the child holds the only write end from now on
+    close(write_fd);
+    let mut buffer = [0u8; 1000];
+    let mut total = 0;
+    loop {
+        let len = read(read_fd, &mut buffer);
+        assert!(len >= 0);
+        if len == 0 {
+            break;
+        }
+        for (i, &byte) in buffer[..len as usize].iter().enumerate() {
+            assert_eq!(byte, byte_at(total + i));
+        }
+        total += len as usize;
+    }
+    assert_eq!(total, LENGTH);
+    let mut exit_code: i32 = 0;
+    assert_eq!(wait(&mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    close(read_fd);
+
+    // nobody can read any more
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    let [read_fd, write_fd] = pipe_fd;
+    close(read_fd);
+    assert_eq!(write(write_fd, b"lost"), -EPIPE);
+    close(write_fd);
+
+    // with a single free descriptor left, neither end is kept
+    let first = dup(STDOUT);
+    assert!(first > 2);
+    let mut last = first;
+    while last >= 0 {
+        last = dup(STDOUT);
+    }
+    assert_eq!(last, -EMFILE);
+    let free = first as usize;
+    assert_eq!(close(free), 0);
+    assert_eq!(pipe(&mut pipe_fd), -EMFILE);
+    assert_eq!(dup(STDOUT), free as isize);
+    assert_eq!(close(free), 0);
+    for fd in first + 1.. {
+        if close(fd as usize) != 0 {
+            break;
+        }
+    }
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    assert_eq!(pipe_fd, [free, free + 1]);
+    close(pipe_fd[0]);
+    close(pipe_fd[1]);
+    println!("Test pipe OK!");
+    0
+}
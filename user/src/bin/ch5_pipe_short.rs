@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, exit, fork, pipe, read, wait, write};
+
+/// 程序行为：子进程写入少于父进程请求的字节数且不关闭写端，而是等待父进程的应答。
+/// 读端应立即返回已有的数据而不是等待缓冲区填满，否则两个进程互相等待。
+
+/// 理想输出：
+/// Test pipe short read OK!
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut data_fd = [0usize; 2];
+    let mut ack_fd = [0usize; 2];
+    assert_eq!(pipe(&mut data_fd), 0);
+    assert_eq!(pipe(&mut ack_fd), 0);
+    let pid = fork();
+    if pid == 0 {
+        close(data_fd[0]);
+        close(ack_fd[1]);
+        for msg in [&b"hello"[..], &b"pipe"[..]] {
+            assert_eq!(write(data_fd[1], msg), msg.len() as isize);
+            let mut ack = [0u8; 1];
+            assert_eq!(read(ack_fd[0], &mut ack), 1);
+        }
+        close(data_fd[1]);
+        exit(0);
+    }
+    close(data_fd[1]);
+    close(ack_fd[0]);
+    let mut buffer = [0u8; 100];
+    for msg in [&b"hello"[..], &b"pipe"[..]] {
+        let len = read(data_fd[0], &mut buffer);
+        assert_eq!(&buffer[..len as usize], msg);
+        assert_eq!(write(ack_fd[1], b"!"), 1);
+    }
+    assert_eq!(read(data_fd[0], &mut buffer), 0);
+    let mut exit_code: i32 = 0;
+    assert_eq!(wait(&mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    close(data_fd[0]);
+    close(ack_fd[1]);
+    println!("Test pipe short read OK!");
+    0
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{fork, get_time, nanosleep, sleep_blocking, waitpid_nohang, TimeSpec, EINVAL};
+
+/// 程序行为：sleep_blocking 和 nanosleep 至少睡眠给定的时间，多个进程同时睡眠时按截止时间先后被唤醒。
+
+/// 理想输出：
+/// Test sleep_blocking OK!
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let start = get_time();
+    sleep_blocking(100);
+    assert!(get_time() - start >= 100);
+    let start = get_time();
+    let mut rem = TimeSpec::default();
+    let req = TimeSpec {
+        sec: 0,
+        nsec: 50_000_000,
+    };
+    assert_eq!(nanosleep(&req, &mut rem), 0);
+    assert!(get_time() - start >= 50);
+    assert_eq!(rem.sec + rem.nsec, 0);
+    let req = TimeSpec {
+        sec: 0,
+        nsec: 1_000_000_000,
+    };
+    assert_eq!(nanosleep(&req, &mut rem), -EINVAL);
+    // the child sleeps shorter and exits first
+    let start = get_time();
+    let pid = fork();
+    if pid == 0 {
+        sleep_blocking(50);
+        return 0;
+    }
+    sleep_blocking(200);
+    let mut exit_code = 0;
+    assert_eq!(waitpid_nohang(pid, &mut exit_code), pid);
+    assert!(get_time() - start >= 200);
+    println!("Test sleep_blocking OK!");
+    0
+}
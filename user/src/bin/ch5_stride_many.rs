@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::ptr::{read_volatile, write_volatile};
+use user_lib::{
+    close, exit, fork, get_time, pipe, read, set_priority, wait, write, yield_, WEXITSTATUS,
+};
+
+/// 程序行为：fork 出数百个子进程，它们阻塞在同一管道上，全部 fork 完成后由父进程同时放行。一半优先级为 16，一半为 8，每个子进程完成相同的计算量后退出。
+/// 按 stride 调度，高优先级组得到 2/3 的 CPU 时间，先退出的一半子进程中绝大多数应属于高优先级组。
+/// 之后再让数百个子进程反复 yield，输出调度开销，用于比较不同实现的速度。
+
+/// 理想输出：
+/// stride: 200 tasks finished in ... ms
+/// stride: 10000 yields among 200 tasks in ... ms
+/// Test stride with many tasks OK!
+
+const TASKS: usize = 200;
+/// Computation of each task, in time slices of 10ms
+const WORK_SLICES: usize = 3;
+const YIELDS: usize = 50;
+const HIGH: i32 = 0;
+const LOW: i32 = 1;
+/// At least this many of the first `TASKS / 2` tasks to finish must be of high priority,
+/// all of them are if every task is let go at the same time
+const HIGH_FIRST: usize = TASKS / 2 * 4 / 5;
+
+fn spin(iters: usize) {
+    let mut acc = 0usize;
+    for i in 0..iters {
+        unsafe { write_volatile(&mut acc, read_volatile(&acc) + i) };
+    }
+}
+
+/// Iterations of [`spin`] per millisecond
+fn calibrate() -> usize {
+    let mut iters = 1000;
+    loop {
+        let start = get_time();
+        spin(iters);
+        let time = get_time() - start;
+        if time >= 50 {
+            return iters / time as usize;
+        }
+        iters *= 2;
+    }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let work = calibrate() * WORK_SLICES * 10;
+    let start = get_time();
+    // every task waits for a byte, which is sent when all of them have been forked
+    let mut pipe_fd = [0usize; 2];
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    for i in 0..TASKS {
+        let pid = fork();
+        if pid == 0 {
+            close(pipe_fd[1]);
+            let group = if i % 2 == 0 { HIGH } else { LOW };
+            set_priority(if group == HIGH { 16 } else { 8 });
+            assert_eq!(read(pipe_fd[0], &mut [0u8]), 1);
+            spin(work);
+            exit(group);
+        }
+        assert!(pid > 0);
+    }
+    close(pipe_fd[0]);
+    assert_eq!(write(pipe_fd[1], &[0u8; TASKS]), TASKS as isize);
+    close(pipe_fd[1]);
+    let mut high_first = 0;
+    let mut exit_code: i32 = 0;
+    for finished in 0..TASKS {
+        assert!(wait(&mut exit_code) > 0);
+        if finished < TASKS / 2 && WEXITSTATUS(exit_code) == HIGH {
+            high_first += 1;
+        }
+    }
+    assert!(
+        high_first >= HIGH_FIRST,
+        "only {} of the first {} tasks to finish are of high priority",
+        high_first,
+        TASKS / 2
+    );
+    println!(
+        "stride: {} tasks finished in {} ms",
+        TASKS,
+        get_time() - start
+    );
+
+    let start = get_time();
+    for _ in 0..TASKS {
+        let pid = fork();
+        if pid == 0 {
+            for _ in 0..YIELDS {
+                yield_();
+            }
+            exit(0);
+        }
+        assert!(pid > 0);
+    }
+    for _ in 0..TASKS {
+        assert!(wait(&mut exit_code) > 0);
+    }
+    println!(
+        "stride: {} yields among {} tasks in {} ms",
+        TASKS * YIELDS,
+        TASKS,
+        get_time() - start
+    );
+    println!("Test stride with many tasks OK!");
+    0
+}
@@ -20,11 +20,23 @@ static TESTS: &[&str] = &[
     "ch4_mmap3\0",
     "ch4_unmap\0",
     "ch4_unmap2\0",
+    "ch4_unmap3\0",
+    "ch4_badptr\0",
     "ch5_spawn0\0",
     "ch5_spawn1\0",
+    "ch5_argv\0",
+    "ch5_exec_bad\0",
+    "ch5_waitpid\0",
+    "ch5_sleep\0",
     "ch5_setprio\0",
+    "ch5_fd\0",
+    "ch5_fd_max\0",
+    "ch5_pipe\0",
+    "ch5_pipe_short\0",
     // "ch5_stride\0",
 ];
+/// Tests that rely on stride scheduling, run only if the kernel is built with it
+static STRIDE_TESTS: &[&str] = &["ch5_stride_many\0"];
 static STEST: &str = "ch5_stride\0";
 
 use user_lib::{spawn, waitpid};
@@ -33,13 +45,18 @@ use user_lib::{spawn, waitpid};
 
 #[no_mangle]
 pub fn main() -> i32 {
-    let mut pid = [0; 20];
-    for (i, &test) in TESTS.iter().enumerate() {
+    let stride_tests: &[&str] = if cfg!(feature = "sched-stride") {
+        STRIDE_TESTS
+    } else {
+        &[]
+    };
+    let mut pid = [0; 32];
+    for (i, &test) in TESTS.iter().chain(stride_tests).enumerate() {
         println!("Usertests: Running {}", test);
         pid[i] = spawn(test);
     }
     let mut xstate: i32 = Default::default();
-    for (i, &test) in TESTS.iter().enumerate() {
+    for (i, &test) in TESTS.iter().chain(stride_tests).enumerate() {
         let wait_pid = waitpid(pid[i] as usize, &mut xstate);
         assert_eq!(pid[i], wait_pid);
         println!(
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    exit, fork, get_time, sys_wait4, sys_waitpid, waitpid, waitpid_exit_code, waitpid_nohang,
+    yield_, ECHILD, EINVAL, WEXITSTATUS, WIFEXITED,
+};
+
+/// 程序行为：WNOHANG 下子进程未退出时 waitpid 立即返回 0，之后阻塞等待子进程退出并检查
+/// WIFEXITED/WEXITSTATUS 编码的退出状态；再以实验 ABI 的 waitpid 等待另一个子进程，
+/// 检查完整的退出码。
+
+/// 理想输出：
+/// Test waitpid nohang OK!
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let pid = fork();
+    if pid == 0 {
+        let start = get_time();
+        while get_time() < start + 100 {
+            yield_();
+        }
+        exit(66778);
+    }
+    let mut status: i32 = 0;
+    assert_eq!(waitpid_nohang(pid, &mut status), 0);
+    assert_eq!(sys_wait4(pid, &mut status as *mut _, 4), -EINVAL);
+    assert_eq!(waitpid(pid as usize, &mut status), pid);
+    assert!(WIFEXITED(status));
+    assert_eq!(WEXITSTATUS(status), 66778 & 0xff);
+    assert_eq!(waitpid_nohang(pid, &mut status), -ECHILD);
+    let pid = fork();
+    if pid == 0 {
+        exit(66778);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(sys_waitpid(pid, &mut exit_code as *mut _, 4), -1);
+    assert_eq!(waitpid_exit_code(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 66778);
+    println!("Test waitpid nohang OK!");
+    0
+}
@@ -4,7 +4,7 @@
 #[macro_use]
 extern crate user_lib;
 
-use user_lib::{exec, fork, wait, yield_};
+use user_lib::{exec, fork, wait, WEXITSTATUS};
 
 #[no_mangle]
 fn main() -> i32 {
@@ -14,13 +14,14 @@ fn main() -> i32 {
         loop {
             let mut exit_code: i32 = 0;
             let pid = wait(&mut exit_code);
-            if pid == -1 {
-                yield_();
-                continue;
+            if pid < 0 {
+                // nothing is left to run, the kernel shuts down after initproc exits
+                break;
             }
             println!(
                 "[initproc] Released a zombie process, pid={}, exit_code={}",
-                pid, exit_code,
+                pid,
+                WEXITSTATUS(exit_code),
             );
         }
     }
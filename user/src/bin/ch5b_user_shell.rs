@@ -12,8 +12,9 @@ const DL: u8 = 0x7fu8;
 const BS: u8 = 0x08u8;
 
 use alloc::string::String;
+use alloc::vec::Vec;
 use user_lib::console::getchar;
-use user_lib::{exec, flush, fork, waitpid};
+use user_lib::{exec, flush, fork, waitpid, WEXITSTATUS};
 
 #[no_mangle]
 pub fn main() -> i32 {
@@ -27,11 +28,24 @@ pub fn main() -> i32 {
             LF | CR => {
                 print!("\n");
                 if !line.is_empty() {
-                    line.push('\0');
+                    let mut args: Vec<String> = line
+                        .split_whitespace()
+                        .map(|arg| {
+                            let mut arg = String::from(arg);
+                            arg.push('\0');
+                            arg
+                        })
+                        .collect();
+                    if args.is_empty() {
+                        args.push(String::from("\0"));
+                    }
+                    let mut args_addr: Vec<*const u8> =
+                        args.iter().map(|arg| arg.as_ptr()).collect();
+                    args_addr.push(core::ptr::null());
                     let pid = fork();
                     if pid == 0 {
                         // child process
-                        if exec(line.as_str(), &[0 as *const u8]) == -1 {
+                        if exec(args[0].as_str(), args_addr.as_slice()) < 0 {
                             println!("Error when executing!");
                             return -4;
                         }
@@ -40,7 +54,11 @@ pub fn main() -> i32 {
                         let mut exit_code: i32 = 0;
                         let exit_pid = waitpid(pid as usize, &mut exit_code);
                         assert_eq!(pid, exit_pid);
-                        println!("Shell: Process {} exited with code {}", pid, exit_code);
+                        println!(
+                            "Shell: Process {} exited with code {}",
+                            pid,
+                            WEXITSTATUS(exit_code)
+                        );
                     }
                     line.clear();
                 }
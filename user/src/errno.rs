@@ -0,0 +1,21 @@
+//! Error numbers returned by the kernel, a failed syscall returns the
+//! negated value
+
+pub const ENOENT: isize = 2;
+pub const EIO: isize = 5;
+pub const E2BIG: isize = 7;
+pub const ENOEXEC: isize = 8;
+pub const EBADF: isize = 9;
+pub const ECHILD: isize = 10;
+pub const ENOMEM: isize = 12;
+pub const EFAULT: isize = 14;
+pub const EEXIST: isize = 17;
+pub const ENOTDIR: isize = 20;
+pub const EISDIR: isize = 21;
+pub const EINVAL: isize = 22;
+pub const EMFILE: isize = 24;
+pub const ENOSPC: isize = 28;
+pub const EPIPE: isize = 32;
+pub const ENAMETOOLONG: isize = 36;
+pub const ENOSYS: isize = 38;
+pub const ENOTEMPTY: isize = 39;
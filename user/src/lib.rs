@@ -5,6 +5,7 @@
 
 #[macro_use]
 pub mod console;
+mod errno;
 mod lang_items;
 mod syscall;
 
@@ -16,6 +17,7 @@ extern crate bitflags;
 use alloc::vec::Vec;
 use buddy_system_allocator::LockedHeap;
 pub use console::{flush, STDIN, STDOUT};
+pub use errno::*;
 pub use syscall::*;
 
 const USER_HEAP_SIZE: usize = 16384;
@@ -98,6 +100,13 @@ impl TimeVal {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TaskStatus {
     UnInit,
@@ -167,6 +176,10 @@ impl Default for Stat {
 bitflags! {
     pub struct StatMode: u32 {
         const NULL  = 0;
+        /// named or anonymous pipe
+        const FIFO  = 0o010000;
+        /// character device
+        const CHR   = 0o020000;
         /// directory
         const DIR   = 0o040000;
         /// ordinary regular file
@@ -248,36 +261,46 @@ pub fn set_priority(prio: isize) -> isize {
     sys_set_priority(prio)
 }
 
-pub fn wait(exit_code: &mut i32) -> isize {
-    loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
-            -2 => {
-                sys_yield();
-            }
-            n => {
-                return n;
-            }
-        }
-    }
+/// Wait for any child, and write its wait status read by `WIFEXITED` and `WEXITSTATUS`
+pub fn wait(status: &mut i32) -> isize {
+    sys_wait4(-1, status as *mut _, 0)
 }
 
-pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
-    loop {
-        match sys_waitpid(pid as isize, exit_code as *mut _) {
-            -2 => {
-                sys_yield();
-            }
-            n => {
-                return n;
-            }
-        }
-    }
+pub fn waitpid(pid: usize, status: &mut i32) -> isize {
+    sys_wait4(pid as isize, status as *mut _, 0)
+}
+
+/// Return 0 at once if the child is still running
+pub fn waitpid_nohang(pid: isize, status: &mut i32) -> isize {
+    sys_wait4(pid, status as *mut _, WNOHANG)
+}
+
+/// Like `waitpid`, but through the waitpid of the lab ABI, which writes the
+/// exit code as passed to `exit` instead of a wait status
+pub fn waitpid_exit_code(pid: usize, exit_code: &mut i32) -> isize {
+    sys_waitpid(pid as isize, exit_code as *mut _, 0)
+}
+
+pub const WNOHANG: usize = 1;
+
+#[allow(non_snake_case)]
+pub fn WIFEXITED(status: i32) -> bool {
+    status & 0x7f == 0
+}
+
+#[allow(non_snake_case)]
+pub fn WEXITSTATUS(status: i32) -> i32 {
+    (status >> 8) & 0xff
 }
 
 pub fn sleep_blocking(sleep_ms: usize) {
     sys_sleep(sleep_ms);
 }
 
+pub fn nanosleep(req: &TimeSpec, rem: &mut TimeSpec) -> isize {
+    sys_nanosleep(req, rem)
+}
+
 pub fn sleep(period_ms: usize) {
     let start = get_time();
     while get_time() < start + period_ms as isize {
@@ -293,7 +316,11 @@ pub fn munmap(start: usize, len: usize) -> isize {
 }
 
 pub fn spawn(path: &str) -> isize {
-    sys_spawn(path)
+    sys_spawn(path, &[core::ptr::null()])
+}
+
+pub fn spawn_args(path: &str, args: &[*const u8]) -> isize {
+    sys_spawn(path, args)
 }
 
 pub fn dup(fd: usize) -> isize {